@@ -1,9 +1,12 @@
 //! Module that handles parsing the command line using clap.
 
+use std::{ffi::OsString, path::PathBuf, time::Duration};
+
 use anyhow::{Result, Context};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 
 use libtagfs::db::TagValuePair;
+use libtagfs::fs::{DuplicateNames, MountConfig};
 
 /// Handles the query command args.
 #[derive(clap::Args, Clone, Debug)]
@@ -22,7 +25,7 @@ pub struct QueryCommand {
 pub struct TagCommand {
     /// Path to apply tag to.
     #[arg(required = true, value_name = "path")]
-    pub path: Utf8PathBuf,
+    pub path: PathBuf,
 
     /// Tag and optional value to apply to path tag(=value)?
     #[arg(required = true, value_name = "tags")]
@@ -35,6 +38,195 @@ pub struct MountCommand {
     /// Directory to mount the file system at.
     #[arg(required = true, value_name = "mount-point")]
     pub mount_point: Utf8PathBuf,
+
+    /// Maximum number of tag directories that can be nested in a path, for
+    /// example /genre/crime/year/1995 is two deep. One disables nesting.
+    #[arg(long, value_name = "depth", default_value_t = 3,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_depth: u64,
+
+    /// Show tagged paths as the real files and directories instead of as
+    /// symlinks, for programs that cannot follow symlinks out of the mount.
+    /// Real files can only be read.
+    #[arg(long)]
+    pub passthrough: bool,
+
+    /// Show the modification times and owners of the tagged paths on their
+    /// symlinks, so that tag directories can be sorted by date.
+    #[arg(long)]
+    pub target_attrs: bool,
+
+    /// Replace a prefix of the tagged paths in the targets of symlinks, for
+    /// when they are mounted somewhere else, for example /media/hdd=/data.
+    /// Can be given more than once, the first matching prefix is replaced.
+    #[arg(long, value_name = "from=to", value_parser = parse_map_root)]
+    pub map_root: Vec<(String, String)>,
+
+    /// Make the targets of symlinks relative to the symlink, so that the
+    /// database works wherever the mount point and the tagged paths are, as
+    /// long as they are in the same place relative to each other.
+    #[arg(long)]
+    pub relative_links: bool,
+
+    /// Only show the paths that match a query, for example
+    /// 'type==film and not genre==horror'.
+    #[arg(long, value_name = "query", conflicts_with = "root_stored_query")]
+    pub root_query: Option<String>,
+
+    /// Only show the paths that match the stored query with this name.
+    #[arg(long, value_name = "name")]
+    pub root_stored_query: Option<String>,
+
+    /// How links to paths with the same file name are told apart: suffix
+    /// (path.txt.0), counter (path (3).txt, numbered by tag mapping), parent
+    /// (dir - path.txt) or hash (path [1a2b3c4d].txt, added to every link).
+    #[arg(long, value_name = "strategy", default_value = "suffix")]
+    pub duplicate_names: DuplicateNames,
+
+    /// Number of seconds the kernel may cache entries and their attributes.
+    #[arg(long, value_name = "seconds", default_value_t = 1)]
+    pub ttl: u64,
+
+    /// Number of seconds between checks for changes to the database made
+    /// while mounted, for example by tagfs tag.
+    #[arg(long, value_name = "seconds", default_value_t = 1,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    pub poll_interval: u64,
+
+    /// Name of the directory that contains the queries.
+    #[arg(long, value_name = "name", default_value = "?")]
+    pub query_dir_name: String,
+
+    /// Name of the directory that mirrors every tagged path. Change it to
+    /// show a tag with the same name.
+    #[arg(long, value_name = "name", default_value = "tags")]
+    pub all_tags_dir_name: String,
+
+    /// Permissions of the directories in octal.
+    #[arg(long, value_name = "mode", default_value = "755",
+          value_parser = parse_mode)]
+    pub dir_perm: u16,
+
+    /// Permissions of the tags files in octal.
+    #[arg(long, value_name = "mode", default_value = "644",
+          value_parser = parse_mode)]
+    pub file_perm: u16,
+
+    /// Owner of every file in the mount. Defaults to the current user.
+    #[arg(long, value_name = "uid")]
+    pub uid: Option<u32>,
+
+    /// Group of every file in the mount. Defaults to the current group.
+    #[arg(long, value_name = "gid")]
+    pub gid: Option<u32>,
+
+    /// Allow other users to access the mount. Requires user_allow_other in
+    /// /etc/fuse.conf.
+    #[arg(long)]
+    pub allow_other: bool,
+
+    /// Do not unmount when tagfs exits.
+    #[arg(long)]
+    pub no_auto_unmount: bool,
+
+    /// Mount read only.
+    #[arg(long)]
+    pub read_only: bool,
+
+    /// Number of threads that serve requests, so that a slow query does not
+    /// hold up other directories.
+    #[arg(long, value_name = "count", default_value_t = 4,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    pub threads: u64,
+
+    /// Run in the background once the filesystem is mounted.
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// File to write the pid of the daemon to. Defaults to a file named after
+    /// the mount point in $XDG_STATE_HOME/tagfs.
+    #[arg(long, value_name = "path", requires = "daemon")]
+    pub pid_file: Option<Utf8PathBuf>,
+
+    /// File the daemon logs to. Defaults to a file named after the mount
+    /// point in $XDG_STATE_HOME/tagfs. Messages at the info level and above
+    /// are logged unless RUST_LOG sets another level.
+    #[arg(long, value_name = "path", requires = "daemon")]
+    pub log_file: Option<Utf8PathBuf>,
+}
+
+impl MountCommand {
+    /// Options to mount the filesystem with.
+    pub fn mount_config(&self) -> MountConfig {
+        let default = MountConfig::default();
+
+        MountConfig {
+            max_depth: self.max_depth as usize,
+            passthrough: self.passthrough,
+            target_attrs: self.target_attrs,
+            ttl: Duration::from_secs(self.ttl),
+            poll_interval: Duration::from_secs(self.poll_interval),
+            map_roots: self.map_root.clone(),
+            relative_links: self.relative_links,
+            root_query: self.root_query.clone(),
+            duplicate_names: self.duplicate_names,
+            query_dir_name: self.query_dir_name.clone(),
+            all_tags_dir_name: self.all_tags_dir_name.clone(),
+            dir_perm: self.dir_perm,
+            file_perm: self.file_perm,
+            uid: self.uid.unwrap_or(default.uid),
+            gid: self.gid.unwrap_or(default.gid),
+            auto_unmount: !self.no_auto_unmount,
+            allow_other: self.allow_other,
+            read_only: self.read_only,
+            threads: self.threads as usize,
+        }
+    }
+}
+
+/// Handles the unmount command args.
+#[derive(clap::Args, Clone, Debug)]
+pub struct UnmountCommand {
+    /// Directory the file system is mounted at.
+    #[arg(required = true, value_name = "mount-point")]
+    pub mount_point: Utf8PathBuf,
+
+    /// Pid file of the daemon serving the mount, if it was mounted with a
+    /// custom --pid-file.
+    #[arg(long, value_name = "path")]
+    pub pid_file: Option<Utf8PathBuf>,
+}
+
+/// Returns the path of a file in $XDG_STATE_HOME/tagfs that belongs to the
+/// mount at mount_point, for example /mnt/tags gives mnt-tags.pid.
+pub fn mount_state_file(mount_point: &Utf8Path, extension: &str)
+    -> Result<Utf8PathBuf>
+{
+    let mut path = std::env::var("XDG_STATE_HOME")
+        .map(Utf8PathBuf::from)
+        .or_else(|_| std::env::var("HOME")
+            .map(|home| Utf8PathBuf::from(home).join(".local/state")))
+        .context("could not find $XDG_STATE_HOME or $HOME.")?;
+
+    let name = mount_point.as_str().trim_matches('/').replace('/', "-");
+    path.push("tagfs");
+    path.push(format!("{name}.{extension}"));
+
+    Ok(path)
+}
+
+/// Parses permission bits written in octal, such as 755.
+fn parse_mode(mode: &str) -> Result<u16, String> {
+    u16::from_str_radix(mode, 8).ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("\"{mode}\" is not an octal mode."))
+}
+
+/// Parses a prefix mapping written as from=to.
+fn parse_map_root(mapping: &str) -> Result<(String, String), String> {
+    mapping.split_once('=')
+        .map(|(from, to)| (String::from(from), String::from(to)))
+        .ok_or_else(|| format!("\"{mapping}\" is not written as from=to."))
 }
 
 /// Handles the tags command args.
@@ -42,7 +234,7 @@ pub struct MountCommand {
 pub struct TagsCommand {
     /// Path to show associated tags.
     #[arg(value_name = "path")]
-    pub path: Option<Utf8PathBuf>,
+    pub path: Option<PathBuf>,
 }
 
 /// Handles the untag command args.
@@ -50,7 +242,7 @@ pub struct TagsCommand {
 pub struct UntagCommand {
     /// Path to remove tag from.
     #[arg(required = true, value_name = "path")]
-    pub path: Utf8PathBuf,
+    pub path: PathBuf,
 
     /// Optional tag and optional value to remove from path tag(=value)?
     #[arg(value_name = "tag")]
@@ -63,7 +255,7 @@ pub struct UntagCommand {
 pub struct AutotagCommand {
     /// Path to directory or file to autotag.
     #[arg(required = true, value_name = "path")]
-    pub path: Utf8PathBuf,
+    pub path: PathBuf,
 
     /// TMDB API key.
     ///
@@ -78,11 +270,11 @@ pub struct AutotagCommand {
 pub struct PrefixCommand {
     /// Prefix to change.
     #[arg(required = true, value_name = "old-prefix")]
-    pub old_prefix: String,
+    pub old_prefix: OsString,
 
     /// New prefix.
     #[arg(required = true, value_name = "new-prefix")]
-    pub new_prefix: String,
+    pub new_prefix: OsString,
 }
 
 /// Handles the edit command args.
@@ -126,6 +318,43 @@ pub struct StoredQueriesCommand {
     pub command: Option<StoredQueriesSubCommand>,
 }
 
+/// Handles the xattr command args.
+#[derive(clap::Subcommand, Clone, Debug)]
+pub enum XattrSubCommand {
+
+    /// Write the tags of every path to the extended attributes of the real
+    /// files.
+    ///
+    /// The attributes of paths that no longer have any tags are only removed
+    /// under the given directory. Elsewhere they are kept, and a rebuild
+    /// would bring their tags back.
+    #[command(name = "sync")]
+    Sync {
+        /// Directory to remove the attributes of untagged paths under.
+        #[arg(value_name = "path")]
+        path: Option<PathBuf>,
+    },
+
+    /// Rebuild the tags of every path under a directory from the extended
+    /// attributes of the real files.
+    ///
+    /// Paths under the directory that no longer exist are removed from the
+    /// database.
+    #[command(name = "rebuild")]
+    Rebuild {
+        /// Directory to scan for extended attributes.
+        #[arg(required = true, value_name = "path")]
+        path: PathBuf,
+    },
+}
+
+/// Wrapper struct for the xattr subcommands.
+#[derive(clap::Args, Clone, Debug)]
+pub struct XattrCommand {
+    #[command(subcommand)]
+    pub command: XattrSubCommand,
+}
+
 /// Contains a subcommand and the specific struct pertaining to it.
 #[derive(clap::Subcommand, Debug)]
 pub enum Command {
@@ -145,6 +374,13 @@ pub enum Command {
     #[command(visible_alias = "mnt", visible_alias = "m")]
     Mount(MountCommand),
 
+    /// Unmount the filesystem and wait for its daemon to exit.
+    #[command(visible_alias = "umount")]
+    Unmount(UnmountCommand),
+
+    /// List the mount points of the mounted filesystems.
+    Mounts,
+
     /// Display tags associated with a path.
     Tags(TagsCommand),
 
@@ -177,6 +413,12 @@ pub enum Command {
 
     /// List, create and delete stored queries in the database.
     StoredQueries(StoredQueriesCommand),
+
+    /// Mirror tags into the extended attributes of the real files.
+    ///
+    /// Unlike the paths in the database, extended attributes follow a file
+    /// when it is moved and are kept by backups such as rsync -X.
+    Xattr(XattrCommand),
 }
 
 /// Contains the parsed arguments from the command line.
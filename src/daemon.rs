@@ -0,0 +1,143 @@
+//! Module that detaches tagfs from the terminal to serve a mount in the
+//! background.
+
+use std::{fs::OpenOptions, os::fd::AsRawFd, time::{Duration, Instant}};
+
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+
+/// How long to wait for the daemon to mount the filesystem or to exit.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check on the daemon while waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Continue in a background process that is detached from the terminal. The
+/// pid of the background process is written to pid_file and anything it
+/// logs is appended to log_file.
+///
+/// Only returns in the background process. The original process exits once
+/// the background process has mounted the filesystem at mount_point.
+///
+/// # Errors
+/// Returns an error in the original process if the background process could
+/// not be started or did not mount the filesystem.
+pub fn daemonize(mount_point: &Utf8Path, pid_file: &Utf8Path,
+                 log_file: &Utf8Path) -> Result<()>
+{
+    for file in [pid_file, log_file] {
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir).with_context(||
+                format!("could not create directory \"{dir}\"."))?;
+        }
+    }
+
+    // the log is opened before forking so that the user sees any error.
+    let log = OpenOptions::new().create(true).append(true).open(log_file)
+        .with_context(|| format!("could not open log file \"{log_file}\"."))?;
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")
+        .context("could not open /dev/null.")?;
+
+    // we fork twice so that the background process is not a session leader
+    // and can never acquire a controlling terminal.
+    match unsafe { libc::fork() } {
+        -1 => {
+            return Err(std::io::Error::last_os_error())
+                .context("could not start the daemon.");
+        }
+        0 => {}
+        child => {
+            let mut status = 0;
+            unsafe { libc::waitpid(child, &mut status, 0) };
+            if status != 0 {
+                bail!("could not start the daemon.");
+            }
+
+            wait_for_mount(mount_point, pid_file, log_file)?;
+            std::process::exit(0);
+        }
+    }
+
+    // from here on we must not return in the intermediate process, so any
+    // error exits it straight away.
+    if unsafe { libc::setsid() } == -1 {
+        unsafe { libc::_exit(1) };
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => unsafe { libc::_exit(1) },
+        0 => {}
+        daemon => {
+            let res = std::fs::write(pid_file, format!("{daemon}\n"));
+            unsafe { libc::_exit(i32::from(res.is_err())) };
+        }
+    }
+
+    std::env::set_current_dir("/")
+        .context("could not change directory to \"/\".")?;
+
+    // the logger writes to stderr, so redirecting it is enough to log to the
+    // file.
+    unsafe {
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO);
+    }
+
+    Ok(())
+}
+
+/// Reads the pid of a daemon from its pid file, or None if there is no
+/// daemon running with that pid.
+pub fn read_pid_file(pid_file: &Utf8Path) -> Option<libc::pid_t> {
+    let pid = std::fs::read_to_string(pid_file).ok()?.trim().parse().ok()?;
+    is_running(pid).then_some(pid)
+}
+
+/// Waits until the daemon with pid has exited.
+///
+/// # Errors
+/// Returns an error if the daemon is still running after the timeout.
+pub fn wait_for_exit(pid: libc::pid_t) -> Result<()> {
+    let start = Instant::now();
+
+    while is_running(pid) {
+        if start.elapsed() > TIMEOUT {
+            bail!("daemon with pid {pid} did not exit.");
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    Ok(())
+}
+
+/// Waits until the daemon has mounted the filesystem at mount_point.
+fn wait_for_mount(mount_point: &Utf8Path, pid_file: &Utf8Path,
+                  log_file: &Utf8Path) -> Result<()>
+{
+    let start = Instant::now();
+
+    loop {
+        let mounts = libtagfs::fs::mounts()
+            .context("could not read the mount table.")?;
+        if mounts.iter().any(|mount| mount == mount_point.as_str()) {
+            return Ok(());
+        }
+
+        if read_pid_file(pid_file).is_none() {
+            bail!("the daemon exited without mounting \"{mount_point}\", \
+                   see \"{log_file}\" for details.");
+        }
+
+        if start.elapsed() > TIMEOUT {
+            bail!("the daemon did not mount \"{mount_point}\" in time, see \
+                   \"{log_file}\" for details.");
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn is_running(pid: libc::pid_t) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
@@ -1,5 +1,7 @@
 //! Module that generates automatic tags from a path.
 
+use std::path::Path;
+
 use anyhow::{anyhow, bail, Result, Context};
 use camino::Utf8Path;
 use chrono::NaiveDateTime;
@@ -9,7 +11,8 @@ use regex::Regex;
 
 use crate::{
     db::{Database, SimpleTagFormatter, TagValuePair, ListFormatter},
-    error::TagFSErrorExt
+    error::TagFSErrorExt,
+    path::decode_path,
 };
 
 static FILM_REGEX: Lazy<Regex> = Lazy::new(|| {
@@ -38,7 +41,8 @@ impl AutoTagger {
         Self { tmdb_key }
     }
 
-    /// Generate and apply the autotags for a particular path.
+    /// Generate and apply the autotags for a particular path, encoded with
+    /// [`crate::path::encode`].
     pub fn autotag(&self, path: &str, db: &mut Database) -> Result<()> {
 
         let path_p = Utf8Path::new(path);
@@ -46,10 +50,10 @@ impl AutoTagger {
 
         let mut path_to_tag = path;
         let tags = if ext == "flac" || ext == "m4a" || ext == "mp3" {
-            generate_music_tags(path)
+            generate_music_tags(&decode_path(path))
 
         } else if ext == "png" || ext == "jpg" || ext == "tif" {
-            generate_image_tags(path)
+            generate_image_tags(&decode_path(path))
 
         } else if ext == "mkv" || ext == "mp4" {
             // file_stem is filename without extension.
@@ -126,7 +130,7 @@ impl AutoTagger {
 
 /// When given a path to a music file, returns the list of automatically
 /// generated tags.
-fn generate_music_tags(path: &str) -> Result<Vec<TagValuePair>> {
+fn generate_music_tags(path: &Path) -> Result<Vec<TagValuePair>> {
     let mut tags = Vec::new();
 
     let metadata = audiotags::Tag::new().read_from_path(path)?;
@@ -349,7 +353,7 @@ fn get_cast_and_crew_tags(tmdb_id: u64, agent: &ureq::Agent, tmdb_key: &str,
     Ok(())
 }
 
-fn generate_image_tags(path: &str) -> Result<Vec<TagValuePair>> {
+fn generate_image_tags(path: &Path) -> Result<Vec<TagValuePair>> {
     let mut tags = Vec::new();
 
     let file = std::fs::File::open(path)?;
@@ -1,4 +1,8 @@
 //! Module that handles interfacing with the sqlite database.
+//!
+//! Paths are passed to and returned from the database encoded with
+//! [`crate::path::encode`], so that paths that are not valid UTF-8 are kept
+//! as they are.
 
 mod query;
 pub use query::{
@@ -7,13 +11,18 @@ pub use query::{
 
 mod edit_repr;
 mod stored_query;
+mod xattr;
+pub use xattr::{parse_xattr_payload, XATTR_PREFIX, XATTR_VALUE_SEPARATOR};
 pub use stored_query::{SanitisedStoredQuery, StoredQuery};
 
+use std::sync::Arc;
+
 use anyhow::{anyhow, bail, Context, Result};
 use indexmap::map::IndexMap;
 use rusqlite::Connection;
 
 use crate::error::TagFSErrorExt;
+use crate::path::decode_path;
 
 /// Analogue to the database table.
 #[derive(Debug)]
@@ -23,6 +32,13 @@ pub struct TagInfo {
     pub takes_value: bool,
 }
 
+impl TagInfo {
+    /// Returns the TagID of the tag, which does not change when it is renamed.
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+}
+
 /// Analogue to the database table.
 #[derive(Debug)]
 pub struct TagMapping {
@@ -53,6 +69,9 @@ impl<'a> Tag<'a> for TagMapping {
 pub struct Database {
     /// Handle to the sqlite connection.
     conn: Connection,
+    /// Query that every path returned from the tag hierarchy must match, see
+    /// [`Database::set_root_query`]. Shared with reopened connections.
+    root_query: Option<Arc<query::Query>>,
 }
 
 impl Database {
@@ -62,11 +81,32 @@ impl Database {
 
         self.initialise_tables()?;
 
-        // remove unused tags if they are no longer referenced.
-        // "OLD" references the row that was just deleted.
+        // remove unused tags if they are no longer referenced by a mapping or
+        // a placeholder. "OLD" references the row that was just deleted.
+        //
+        // Older databases have a version of this trigger that does not know
+        // about placeholders, so it is always recreated.
+        self.conn.execute_batch(
+            "DROP TRIGGER IF EXISTS RemoveUnusedTags;
+             CREATE TRIGGER RemoveUnusedTags AFTER DELETE ON TagMapping
+             BEGIN
+                DELETE FROM Tag
+                WHERE
+                    Tag.TagID = OLD.TagID
+                    AND NOT EXISTS(
+                        SELECT TRUE
+                        FROM TagMapping
+                        WHERE TagMapping.TagID = OLD.TagID)
+                    AND NOT EXISTS(
+                        SELECT TRUE
+                        FROM TagPlaceholder
+                        WHERE TagPlaceholder.TagID = OLD.TagID);
+             END"
+        )?;
+
         self.conn.execute(
             "CREATE TRIGGER IF NOT EXISTS
-             RemoveUnusedTags AFTER DELETE ON TagMapping
+             RemoveUnusedPlaceholderTags AFTER DELETE ON TagPlaceholder
              BEGIN
                 DELETE FROM Tag
                 WHERE
@@ -74,7 +114,27 @@ impl Database {
                     AND NOT EXISTS(
                         SELECT TRUE
                         FROM TagMapping
-                        WHERE TagMapping.TagID = OLD.TagID);
+                        WHERE TagMapping.TagID = OLD.TagID)
+                    AND NOT EXISTS(
+                        SELECT TRUE
+                        FROM TagPlaceholder
+                        WHERE TagPlaceholder.TagID = OLD.TagID);
+             END",
+            []
+        )?;
+
+        // a placeholder is no longer needed once the tag (or tag value pair)
+        // it stands in for is used by a mapping. "NEW" references the row
+        // that was just inserted.
+        self.conn.execute(
+            "CREATE TRIGGER IF NOT EXISTS
+             RemoveUsedPlaceholders AFTER INSERT ON TagMapping
+             BEGIN
+                DELETE FROM TagPlaceholder
+                WHERE
+                    TagPlaceholder.TagID = NEW.TagID
+                    AND (TagPlaceholder.Value IS NULL
+                        OR TagPlaceholder.Value = NEW.Value);
              END",
             []
         )?;
@@ -110,6 +170,18 @@ impl Database {
             UNIQUE(TagID, ValueUniqConstraint, Path)
         )")?;
 
+        // Placeholders allow a tag, or a value of a tag, to exist without
+        // being applied to any path. They are created by making directories
+        // in the filesystem. A NULL value is a placeholder for the tag itself.
+        self.conn.execute_batch("CREATE TABLE IF NOT EXISTS TagPlaceholder (
+            TagPlaceholderID INTEGER PRIMARY KEY,
+            TagID INTEGER NOT NULL,
+            Value TEXT,
+            ValueUniqConstraint GENERATED ALWAYS AS (COALESCE(Value, 'NULL')),
+            FOREIGN KEY(TagID) REFERENCES Tag(TagID),
+            UNIQUE(TagID, ValueUniqConstraint)
+        )")?;
+
         self.conn.execute_batch("CREATE TABLE IF NOT EXISTS StoredQueries (
             StoredQueryID INTEGER PRIMARY KEY,
             Name TEXT NOT NULL,
@@ -126,9 +198,17 @@ impl Database {
             SELECT StoredQueries.Name, StoredQueries.Query FROM StoredQueries
         ")?;
 
-        let stored_queries = stmt.query_map([], |row| Ok(StoredQuery {
-            name: row.get(0)?, query: row.get(1)?
-        }))?.collect::<rusqlite::Result<_>>()?;
+        let mut stored_queries: Vec<StoredQuery> = stmt.query_map([],
+            |row| Ok(StoredQuery { name: row.get(0)?, query: row.get(1)? })
+        )?.collect::<rusqlite::Result<_>>()?;
+
+        if let Some(root_query) = &self.root_query {
+            stored_queries.retain(|stored_query| {
+                let paths = query::Query::from_raw(&stored_query.query, false)
+                    .and_then(|query| query.and(root_query).execute(self));
+                paths.is_ok_and(|paths| !paths.is_empty())
+            });
+        }
 
         Ok(stored_queries)
     }
@@ -156,6 +236,39 @@ impl Database {
         Ok(n != 0)
     }
 
+    /// Only return the paths that match query from [`Database::all_tags`],
+    /// [`Database::values`], [`Database::paths_with_tag`],
+    /// [`Database::paths_with_prefix`], [`Database::query`] and the functions
+    /// that filter them, so that only part of the database is shown. Tags and
+    /// values that only exist as placeholders are still returned.
+    ///
+    /// The counts, [`Database::invalid_paths`] and
+    /// [`Database::stored_queries`] are limited to the same paths, so stored
+    /// queries that match none of them are not returned.
+    pub fn set_root_query(&mut self, query: &str) -> Result<()> {
+        let root_query = query::Query::from_raw(query, false)?;
+
+        // the query is only checked by SQLite once it is prepared.
+        self.conn.prepare_cached(&root_query.to_path_subquery().0)
+            .context("invalid query.")?;
+
+        self.root_query = Some(Arc::new(root_query));
+        Ok(())
+    }
+
+    /// Returns a condition on TagMapping.Path that only matches the paths of
+    /// the root query, or nothing if there is none, along with its
+    /// parameters.
+    fn root_condition(&self) -> (String, &[String]) {
+        match &self.root_query {
+            Some(root_query) => {
+                let (subquery, params) = root_query.to_path_subquery();
+                (format!("AND TagMapping.Path IN ({subquery})"), params)
+            }
+            None => (String::new(), &[]),
+        }
+    }
+
     /// This function tries to find a tag matching the str in the database, if
     /// it does not exist it returns None.
     pub fn get_tag(&self, tag: &str) -> Option<TagInfo> {
@@ -187,6 +300,262 @@ impl Database {
         })
     }
 
+    /// Returns true if a tag is not applied to any path and has no value
+    /// placeholders. The kind of an unused tag can still be changed.
+    fn tag_is_unused(&self, tag: &TagInfo) -> Result<bool> {
+        let used = self.conn.query_row(
+            "SELECT
+                EXISTS(
+                    SELECT TRUE FROM TagMapping
+                    WHERE TagMapping.TagID = ?1)
+                OR EXISTS(
+                    SELECT TRUE FROM TagPlaceholder
+                    WHERE TagPlaceholder.TagID = ?1
+                        AND TagPlaceholder.Value IS NOT NULL)",
+            rusqlite::params![tag.id],
+            |row| row.get::<_, bool>(0)
+        )?;
+
+        Ok(!used)
+    }
+
+    /// Change whether a tag takes a value.
+    fn set_takes_value(&mut self, tag: &mut TagInfo, takes_value: bool)
+        -> Result<()>
+    {
+        self.conn.execute(
+            "UPDATE Tag SET TakesValue = ? WHERE Tag.TagID = ?",
+            rusqlite::params![takes_value, tag.id]
+        )?;
+
+        tag.takes_value = takes_value;
+        Ok(())
+    }
+
+    /// Create a tag that is not applied to any path.
+    ///
+    /// It can fail if a tag already exists with the same name.
+    pub fn create_empty_tag(&mut self, tag_name: &str) -> Result<()> {
+        if self.get_tag(tag_name).is_some() {
+            bail!("tag \"{tag_name}\" already exists.");
+        }
+
+        let tag = self.create_tag(tag_name, false)?;
+
+        self.conn.execute(
+            "INSERT INTO TagPlaceholder (TagID, Value) VALUES (?, NULL)",
+            rusqlite::params![tag.id]
+        )?;
+
+        Ok(())
+    }
+
+    /// Create a value for a tag that is not applied to any path. The tag is
+    /// created if it does not already exist.
+    ///
+    /// It can fail if the value already exists, or if the tag is already in
+    /// use without a value.
+    pub fn create_empty_value(&mut self, tag_name: &str, value: &str)
+        -> Result<()>
+    {
+        let tag = if let Some(mut tag) = self.get_tag(tag_name) {
+            if !tag.takes_value {
+                if !self.tag_is_unused(&tag)? {
+                    bail!("tag \"{tag_name}\" does not take a value.");
+                }
+                self.set_takes_value(&mut tag, true)?;
+            }
+            tag
+        } else {
+            self.create_tag(tag_name, true)?
+        };
+
+        if self.values(tag_name)?.iter().any(|v| v == value) {
+            bail!("tag \"{tag_name}\" already has value \"{value}\".");
+        }
+
+        self.conn.execute(
+            "INSERT INTO TagPlaceholder (TagID, Value) VALUES (?, ?)",
+            rusqlite::params![tag.id, value]
+        )?;
+
+        Ok(())
+    }
+
+    /// Remove a tag from every path and delete it from the database.
+    pub fn remove_tag(&mut self, tag_name: &str) -> Result<()> {
+        let Some(tag) = self.get_tag(tag_name) else {
+            bail!("could not remove tag \"{tag_name}\". Does it exist?");
+        };
+
+        let tx = self.conn.transaction()?;
+
+        tx.execute("DELETE FROM TagPlaceholder WHERE TagID = ?",
+            rusqlite::params![tag.id])?;
+        tx.execute("DELETE FROM TagMapping WHERE TagID = ?",
+            rusqlite::params![tag.id])?;
+        tx.execute("DELETE FROM Tag WHERE TagID = ?",
+            rusqlite::params![tag.id])?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Remove a value of a tag from every path. The tag itself is removed if
+    /// it no longer has any values.
+    pub fn remove_value(&mut self, tag_name: &str, value: &str) -> Result<()> {
+        let Some(tag) = self.get_tag(tag_name) else {
+            bail!("could not remove tag \"{tag_name}\". Does it exist?");
+        };
+
+        let tx = self.conn.transaction()?;
+
+        let n = tx.execute(
+            "DELETE FROM TagPlaceholder WHERE TagID = ? AND Value = ?",
+            rusqlite::params![tag.id, value])?;
+        let m = tx.execute(
+            "DELETE FROM TagMapping WHERE TagID = ? AND Value = ?",
+            rusqlite::params![tag.id, value])?;
+
+        if n + m == 0 {
+            bail!("could not remove tag \"{tag_name}={value}\". \
+                   Does it exist?");
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Rename a tag.
+    ///
+    /// It can fail if a tag already exists with the new name.
+    pub fn rename_tag(&mut self, tag_name: &str, new_tag_name: &str)
+        -> Result<()>
+    {
+        if self.get_tag(new_tag_name).is_some() {
+            bail!("tag \"{new_tag_name}\" already exists.");
+        }
+
+        let n = self.conn.execute(
+            "UPDATE Tag SET Name = ? WHERE Tag.Name = ?",
+            rusqlite::params![new_tag_name, tag_name]
+        )?;
+
+        if n == 0 {
+            bail!("could not rename tag \"{tag_name}\". Does it exist?");
+        }
+
+        Ok(())
+    }
+
+    /// Rename a value of a tag on every path.
+    ///
+    /// It can fail if the tag already has the new value.
+    pub fn rename_value(&mut self, tag_name: &str, value: &str,
+                        new_value: &str) -> Result<()>
+    {
+        let Some(tag) = self.get_tag(tag_name) else {
+            bail!("could not rename tag \"{tag_name}={value}\". \
+                   Does it exist?");
+        };
+
+        if self.values(tag_name)?.iter().any(|v| v == new_value) {
+            bail!("tag \"{tag_name}\" already has value \"{new_value}\".");
+        }
+
+        let tx = self.conn.transaction()?;
+
+        let n = tx.execute(
+            "UPDATE TagMapping SET Value = ? WHERE TagID = ? AND Value = ?",
+            rusqlite::params![new_value, tag.id, value])?;
+        let m = tx.execute(
+            "UPDATE TagPlaceholder SET Value = ?
+             WHERE TagID = ? AND Value = ?",
+            rusqlite::params![new_value, tag.id, value])?;
+
+        if n + m == 0 {
+            bail!("could not rename tag \"{tag_name}={value}\". \
+                   Does it exist?");
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Replace a tag value pair on a path with another. Either both changes
+    /// are made or neither is.
+    pub fn retag(&mut self, path: &str, tag_name: &str, value: Option<&str>,
+                 new_tag_name: &str, new_value: Option<&str>) -> Result<()>
+    {
+        self.with_savepoint(|db| {
+            db.untag(path, tag_name, value)?;
+            db.tag(path, new_tag_name, new_value)
+        })
+    }
+
+    /// Run a function inside a savepoint so that all of its changes to the
+    /// database are rolled back if it returns an error.
+    fn with_savepoint<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>)
+        -> Result<T>
+    {
+        self.conn.execute_batch("SAVEPOINT TagFS")?;
+
+        let res = f(self);
+
+        if res.is_ok() {
+            self.conn.execute_batch("RELEASE TagFS")?;
+        } else {
+            self.conn.execute_batch("ROLLBACK TO TagFS; RELEASE TagFS")?;
+        }
+
+        res
+    }
+
+    /// Tag a path like [`Database::tag`], as long as the path matches the
+    /// root query once it is tagged, see [`Database::set_root_query`].
+    /// Returns false and leaves the database as it was if it does not.
+    pub fn tag_in_root(&mut self, path: &str, tag_name: &str,
+                       value: Option<&str>)
+        -> Result<bool>
+    {
+        self.conn.execute_batch("SAVEPOINT TagInRoot")?;
+
+        let res = self.tag(path, tag_name, value)
+            .and_then(|()| self.matches_root_query(path));
+
+        if matches!(res, Ok(true)) {
+            self.conn.execute_batch("RELEASE TagInRoot")?;
+        } else {
+            self.conn.execute_batch(
+                "ROLLBACK TO TagInRoot; RELEASE TagInRoot")?;
+        }
+
+        res
+    }
+
+    /// Returns true if path matches the root query, or if there is none.
+    fn matches_root_query(&self, path: &str) -> Result<bool> {
+        let (root_condition, root_params) = self.root_condition();
+        if root_condition.is_empty() {
+            return Ok(true);
+        }
+
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT EXISTS(
+                SELECT TRUE
+                FROM TagMapping
+                WHERE TagMapping.Path = ? {root_condition})"
+        ))?;
+
+        let params = std::iter::once(path)
+            .chain(root_params.iter().map(String::as_str));
+
+        let matches = stmt.query_row(rusqlite::params_from_iter(params),
+            |row| row.get(0))?;
+
+        Ok(matches)
+    }
+
     /// Helper function to perform a manual tag.
     pub fn tag(&mut self, path: &str, tag_name: &str, value: Option<&str>)
         -> Result<()>
@@ -204,7 +573,15 @@ impl Database {
                  auto: bool)
         -> Result<()>
     {
-        let tag = if let Some(tag) = self.get_tag(tag_name) {
+        let tag = if let Some(mut tag) = self.get_tag(tag_name) {
+            // an empty tag created from the filesystem only settles whether
+            // it takes a value once it is first used.
+            if tag.takes_value != value.is_some()
+                && self.tag_is_unused(&tag)?
+            {
+                self.set_takes_value(&mut tag, value.is_some())?;
+            }
+
             if tag.takes_value && value.is_none() {
                 bail!("tag \"{}\" takes a value but one was not given",
                       tag_name);
@@ -259,6 +636,7 @@ impl Database {
         //       trigger should have already wiped it, but better to be safe
         //       than sorry.
         self.conn.execute_batch("
+            DELETE FROM TagPlaceholder;
             DELETE FROM TagMapping;
             DELETE FROM Tag;
         ")?;
@@ -272,6 +650,26 @@ impl Database {
         Ok(())
     }
 
+    /// Returns the tags of a path as extended attributes, as a map from
+    /// attribute name to payload.
+    pub fn xattrs(&self, path: &str)
+        -> Result<std::collections::BTreeMap<String, Vec<u8>>>
+    {
+        xattr::xattrs(self, path)
+    }
+
+    /// Write the tags of every path to the extended attributes of the real
+    /// files, and remove them from the untagged paths under root if given.
+    pub fn sync_to_xattrs(&self, root: Option<&str>) -> Result<()> {
+        xattr::sync_to_xattrs(self, root)
+    }
+
+    /// Rebuild the tags of every path under root from the extended attributes
+    /// of the real files.
+    pub fn sync_from_xattrs(&mut self, root: &str) -> Result<()> {
+        xattr::sync_from_xattrs(self, root)
+    }
+
     /// Returns all tagmappings.
     fn dump(&self) -> Result<IndexMap<String, Vec<TagMapping>>> {
         let rows = self.tags_inner(None)?;
@@ -335,57 +733,197 @@ impl Database {
     pub fn paths_with_tag(&mut self, tag: &str, value: Option<&str>)
         -> Result<Vec<(String, u64)>>
     {
-        let mut stmt = if value.is_some() {
-            self.conn.prepare_cached(
-                "SELECT TagMapping.Path, TagMapping.TagMappingID
-                FROM TagMapping INNER JOIN Tag ON Tag.TagID = TagMapping.TagID
-                WHERE Tag.Name = ? AND TagMapping.Value = ?
-                ORDER BY TagMapping.TagMappingID"
-            )?
+        let (root_condition, root_params) = self.root_condition();
+
+        let value_condition = if value.is_some() {
+            "AND TagMapping.Value = ?"
         } else {
-            self.conn.prepare_cached(
-                "SELECT TagMapping.Path, TagMapping.TagMappingID
-                FROM TagMapping INNER JOIN Tag ON Tag.TagID = TagMapping.TagID
-                WHERE Tag.Name = ?
-                ORDER BY TagMapping.TagMappingID"
-            )?
+            ""
         };
 
-        let tags = if value.is_some() {
-            stmt.query_map(rusqlite::params![tag, value],
-                    |row| Ok((row.get(0)?, row.get(1)?)))?
-                .collect::<rusqlite::Result<_>>()
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT TagMapping.Path, TagMapping.TagMappingID
+            FROM TagMapping INNER JOIN Tag ON Tag.TagID = TagMapping.TagID
+            WHERE Tag.Name = ? {value_condition} {root_condition}
+            ORDER BY TagMapping.TagMappingID"
+        ))?;
+
+        let params = std::iter::once(tag).chain(value)
+            .chain(root_params.iter().map(String::as_str));
+
+        let tags = stmt.query_map(rusqlite::params_from_iter(params),
+                |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(tags)
+    }
+
+    /// Returns a list of paths tagged with a particular tag, like
+    /// [`Database::paths_with_tag`], but only those paths that also have every
+    /// tag value pair in filter.
+    pub fn paths_with_tag_matching(&mut self, tag: &str, value: Option<&str>,
+                                   filter: &[TagValuePair])
+        -> Result<Vec<(String, u64)>>
+    {
+        if filter.is_empty() {
+            return self.paths_with_tag(tag, value);
+        }
+
+        let filter = query::Query::from_tags(filter)?;
+        let (subquery, filter_params) = filter.to_path_subquery();
+
+        let value_condition = if value.is_some() {
+            "AND TagMapping.Value = ?"
         } else {
-            stmt.query_map(rusqlite::params![tag],
-                    |row| Ok((row.get(0)?, row.get(1)?)))?
-                .collect::<rusqlite::Result<_>>()
-        }?;
+            ""
+        };
+
+        let (root_condition, root_params) = self.root_condition();
+
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT TagMapping.Path, TagMapping.TagMappingID
+            FROM TagMapping INNER JOIN Tag ON Tag.TagID = TagMapping.TagID
+            WHERE Tag.Name = ? {value_condition}
+                AND TagMapping.Path IN ({subquery}) {root_condition}
+            ORDER BY TagMapping.TagMappingID"
+        ))?;
+
+        let params = std::iter::once(tag).chain(value)
+            .chain(filter_params.iter().map(String::as_str))
+            .chain(root_params.iter().map(String::as_str));
+
+        let paths = stmt.query_map(rusqlite::params_from_iter(params),
+                |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(paths)
+    }
+
+    /// Returns the values of a particular tag, like [`Database::values`], but
+    /// only those used by paths that have every tag value pair in filter.
+    pub fn values_matching(&mut self, tag: &str, filter: &[TagValuePair])
+        -> Result<Vec<String>>
+    {
+        if filter.is_empty() {
+            return self.values(tag);
+        }
+
+        let filter = query::Query::from_tags(filter)?;
+        let (subquery, filter_params) = filter.to_path_subquery();
+
+        let (root_condition, root_params) = self.root_condition();
+
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT TagMapping.Value
+            FROM TagMapping INNER JOIN Tag ON Tag.TagID = TagMapping.TagID
+            WHERE Tag.Name = ? AND TagMapping.Path IN ({subquery})
+                {root_condition}
+            GROUP BY TagMapping.Value
+            ORDER BY MIN(TagMapping.TagMappingID) ASC"
+        ))?;
+
+        let params = std::iter::once(tag)
+            .chain(filter_params.iter().map(String::as_str))
+            .chain(root_params.iter().map(String::as_str));
+
+        let values = stmt.query_map(rusqlite::params_from_iter(params),
+                |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(values)
+    }
+
+    /// Returns the names of the tags used by paths that have every tag value
+    /// pair in filter.
+    pub fn tags_matching(&mut self, filter: &[TagValuePair])
+        -> Result<Vec<String>>
+    {
+        if filter.is_empty() {
+            return self.all_tags();
+        }
+
+        let filter = query::Query::from_tags(filter)?;
+        let (subquery, filter_params) = filter.to_path_subquery();
+
+        let (root_condition, root_params) = self.root_condition();
+
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT Tag.Name
+            FROM TagMapping INNER JOIN Tag ON Tag.TagID = TagMapping.TagID
+            WHERE TagMapping.Path IN ({subquery}) {root_condition}
+            GROUP BY Tag.TagID
+            ORDER BY Tag.TagID"
+        ))?;
+
+        let params = filter_params.iter().chain(root_params);
+
+        let tags = stmt.query_map(rusqlite::params_from_iter(params),
+                |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
 
         Ok(tags)
     }
 
-    /// Returns all values used for a particular tag.
+    /// Returns all values used for a particular tag, followed by the values
+    /// that only exist as placeholders.
     pub fn values(&mut self, tag: &str) -> Result<Vec<String>> {
-        let mut stmt = self.conn.prepare_cached(
-            "SELECT DISTINCT TagMapping.Value
-            FROM TagMapping INNER JOIN Tag ON Tag.TagID = TagMapping.TagID
-            WHERE Tag.Name = ?
-            ORDER BY TagMapping.TagMappingID ASC"
-        )?;
+        let (root_condition, root_params) = self.root_condition();
+
+        // the parameters of the root condition are numbered after ?1.
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT Value FROM (
+                SELECT
+                    TagMapping.Value AS Value, FALSE AS Placeholder,
+                    TagMapping.TagMappingID AS ID
+                FROM TagMapping INNER JOIN Tag ON Tag.TagID = TagMapping.TagID
+                WHERE Tag.Name = ?1 {root_condition}
+                UNION ALL
+                SELECT
+                    TagPlaceholder.Value, TRUE, TagPlaceholder.TagPlaceholderID
+                FROM TagPlaceholder
+                    INNER JOIN Tag ON Tag.TagID = TagPlaceholder.TagID
+                WHERE Tag.Name = ?1 AND TagPlaceholder.Value IS NOT NULL
+            )
+            GROUP BY Value
+            ORDER BY MIN(Placeholder) ASC, MIN(ID) ASC"
+        ))?;
+
+        let params = std::iter::once(tag)
+            .chain(root_params.iter().map(String::as_str));
 
-        let values = stmt.query_map([tag], |row| row.get(0))?
-                        .collect::<rusqlite::Result<_>>();
+        let values = stmt.query_map(rusqlite::params_from_iter(params),
+                |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>();
         values.or_else(|e| Err(e).with_context(||
             format!("tag \"{tag}\" does not take any values")))
     }
 
     /// Returns the names of all tags that appear in the database.
     pub fn all_tags(&mut self) -> Result<Vec<String>> {
-        let mut stmt = self.conn.prepare_cached(
-            "SELECT Name FROM Tag ORDER BY Tag.TagID"
-        )?;
+        let (root_condition, root_params) = self.root_condition();
 
-        let tags = stmt.query_map([], |row| row.get(0))?
+        let mut stmt = if root_condition.is_empty() {
+            self.conn.prepare_cached(
+                "SELECT Name FROM Tag ORDER BY Tag.TagID"
+            )?
+        } else {
+            self.conn.prepare_cached(&format!(
+                "SELECT Name FROM Tag
+                WHERE
+                    EXISTS(
+                        SELECT TRUE
+                        FROM TagMapping
+                        WHERE TagMapping.TagID = Tag.TagID {root_condition})
+                    OR EXISTS(
+                        SELECT TRUE
+                        FROM TagPlaceholder
+                        WHERE TagPlaceholder.TagID = Tag.TagID)
+                ORDER BY Tag.TagID"
+            ))?
+        };
+
+        let tags = stmt.query_map(rusqlite::params_from_iter(root_params),
+                |row| row.get(0))?
             .collect::<rusqlite::Result<_>>()?;
 
         Ok(tags)
@@ -447,11 +985,67 @@ impl Database {
         Ok(())
     }
 
+    /// Replace all the manual tags of a path. Any tags that are already
+    /// applied to the path as autotags are left as they are. Either all tags
+    /// are replaced or none are.
+    pub fn replace_manual_tags(&mut self, path: &str, tags: &[TagValuePair])
+        -> Result<()>
+    {
+        self.with_savepoint(|db| {
+            db.conn.execute(
+                "DELETE FROM TagMapping
+                WHERE TagMapping.Path = ? AND NOT TagMapping.Auto",
+                rusqlite::params![path]
+            )?;
+
+            for tag in tags {
+                let res = db.tag(path, &tag.tag, tag.value.as_deref());
+
+                // the tag is either listed twice or is already an autotag.
+                if !res.is_sql_unique_cons_err() {
+                    res?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Set a tag on a path, replacing any values it already has for that tag.
+    /// The tag is applied without a value if values is empty.
+    pub fn set_tag(&mut self, path: &str, tag_name: &str, values: &[&str])
+        -> Result<()>
+    {
+        self.with_savepoint(|db| {
+            db.conn.execute(
+                "DELETE FROM TagMapping
+                 WHERE TagMapping.Path = ? AND
+                    TagMapping.TagID in
+                        (SELECT Tag.TagID
+                        FROM Tag
+                        WHERE Tag.Name = ?)",
+                rusqlite::params![path, tag_name]
+            )?;
+
+            if values.is_empty() {
+                db.tag(path, tag_name, None)?;
+            }
+            for value in values {
+                db.tag(path, tag_name, Some(value))?;
+            }
+
+            Ok(())
+        })
+    }
+
     /// Build and execute a user query.
     pub fn query(&mut self, query: &str, case_sensitive: bool)
         -> Result<Vec<(String, u64)>>
     {
-        let query = query::Query::from_raw(query, case_sensitive)?;
+        let mut query = query::Query::from_raw(query, case_sensitive)?;
+        if let Some(root_query) = &self.root_query {
+            query = query.and(root_query);
+        }
 
         query.execute(self)
             .map_err(|e| e.context("invalid query."))
@@ -462,19 +1056,88 @@ impl Database {
             .replace('%', "\\%")
             .replace('_', "\\_");
 
-        let mut stmt = self.conn.prepare_cached("
+        let (root_condition, root_params) = self.root_condition();
+
+        let mut stmt = self.conn.prepare_cached(&format!("
             SELECT DISTINCT TagMapping.Path
             FROM TagMapping
             WHERE TagMapping.Path LIKE (? || '%') ESCAPE '\\'
+                {root_condition}
             ORDER BY TagMapping.TagMappingID
-        ")?;
+        "))?;
 
-        let paths = stmt.query_map(rusqlite::params![escaped_prefix],
+        let params = std::iter::once(&escaped_prefix).chain(root_params);
+
+        let paths = stmt.query_map(rusqlite::params_from_iter(params),
             |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
 
         Ok(paths)
     }
 
+    /// Returns the number of distinct paths that have at least one tag.
+    pub fn path_count(&self) -> Result<u64> {
+        let (root_condition, root_params) = self.root_condition();
+
+        let count = self.conn.query_row(&format!(
+                "SELECT COUNT(DISTINCT TagMapping.Path) FROM TagMapping
+                WHERE TRUE {root_condition}"),
+            rusqlite::params_from_iter(root_params), |row| row.get(0))?;
+
+        Ok(count)
+    }
+
+    /// Returns the number of tags, including the tags that are not applied to
+    /// any path.
+    pub fn tag_count(&self) -> Result<u64> {
+        let (root_condition, root_params) = self.root_condition();
+
+        // the same tags as all_tags returns.
+        let count = if root_condition.is_empty() {
+            self.conn.query_row("SELECT COUNT(*) FROM Tag",
+                [], |row| row.get(0))?
+        } else {
+            self.conn.query_row(&format!(
+                    "SELECT COUNT(*) FROM Tag
+                    WHERE
+                        EXISTS(
+                            SELECT TRUE
+                            FROM TagMapping
+                            WHERE
+                                TagMapping.TagID = Tag.TagID {root_condition})
+                        OR EXISTS(
+                            SELECT TRUE
+                            FROM TagPlaceholder
+                            WHERE TagPlaceholder.TagID = Tag.TagID)"),
+                rusqlite::params_from_iter(root_params), |row| row.get(0))?
+        };
+
+        Ok(count)
+    }
+
+    /// Returns the number of times a tag or a value of a tag is applied to a
+    /// path.
+    pub fn mapping_count(&self) -> Result<u64> {
+        let (root_condition, root_params) = self.root_condition();
+
+        let count = self.conn.query_row(&format!(
+                "SELECT COUNT(*) FROM TagMapping WHERE TRUE {root_condition}"),
+            rusqlite::params_from_iter(root_params), |row| row.get(0))?;
+
+        Ok(count)
+    }
+
+    /// Returns the TagMappingID the next mapping will be given, once the
+    /// mapping with the id removed is deleted if there is one. SQLite gives a
+    /// new row one more than the largest id in use.
+    pub fn next_tag_mapping_id(&self, removed: Option<u64>) -> Result<u64> {
+        let id = self.conn.query_row(
+            "SELECT IFNULL(MAX(TagMappingID), 0) + 1 FROM TagMapping
+            WHERE TagMappingID IS NOT ?",
+            [removed], |row| row.get(0))?;
+
+        Ok(id)
+    }
+
     /// Search and replace paths in the database that match the given
     /// old_prefix and replace it with the new_prefix.
     pub fn prefix_change(&mut self, old_prefix: &str, new_prefix: &str)
@@ -506,18 +1169,74 @@ impl Database {
         Ok(path)
     }
 
+    /// Returns a number that changes whenever the database is modified, either
+    /// through this connection or by any other connection, for example a
+    /// tagfs command run while the filesystem is mounted.
+    pub fn version(&self) -> Result<u64> {
+        let version = self.conn.query_row(
+            "SELECT data_version + total_changes() FROM pragma_data_version",
+            [], |row| row.get(0))?;
+
+        Ok(version)
+    }
+
+    /// Switches the database to write-ahead logging, so that connections
+    /// opened with [`Database::reopen`] can read while this connection
+    /// writes. The mode is stored in the database file.
+    pub fn enable_wal(&self) -> Result<()> {
+        let mode: String = self.conn.query_row("PRAGMA journal_mode = WAL",
+            [], |row| row.get(0))?;
+
+        if mode != "wal" {
+            bail!("could not enable write-ahead logging, the journal mode is \
+                   \"{mode}\".");
+        }
+
+        Ok(())
+    }
+
+    /// Opens another connection to the same database, for example to read
+    /// from another thread. The database must already be initialised, and
+    /// cannot be in memory.
+    pub fn reopen(&self) -> Result<Database> {
+        let path = self.conn.path()
+            .filter(|path| *path != std::path::Path::new(":memory:"))
+            .ok_or_else(|| anyhow!("an in-memory database cannot be \
+                                    reopened."))?;
+
+        let db = Database {
+            conn: Connection::open(path)?,
+            root_query: self.root_query.clone(),
+        };
+        db.conn.execute("PRAGMA foreign_keys = ON", [])?;
+
+        Ok(db)
+    }
+
     /// Returns true if all paths in the database point to a real existing path
     /// in the filesystem.
     pub fn all_paths_valid(&self) -> Result<bool> {
-        let mut stmt = self.conn.prepare_cached("
-            SELECT DISTINCT TagMapping.Path FROM TagMapping
-        ")?;
+        Ok(self.invalid_paths()?.is_empty())
+    }
 
-        let all_valid = stmt.query_map([], |row| row.get::<_, String>(0))?
-            .all(|path| path.map_or(false,
-                |path| camino::Utf8Path::new(&path).exists()));
+    /// Returns the paths in the database that do not point to a real existing
+    /// path in the filesystem, in alphabetical order.
+    pub fn invalid_paths(&self) -> Result<Vec<String>> {
+        let (root_condition, root_params) = self.root_condition();
 
-        Ok(all_valid)
+        let mut stmt = self.conn.prepare_cached(&format!("
+            SELECT DISTINCT TagMapping.Path FROM TagMapping
+            WHERE TRUE {root_condition}
+            ORDER BY TagMapping.Path
+        "))?;
+
+        let params = rusqlite::params_from_iter(root_params);
+        let paths = stmt.query_map(params, |row| row.get::<_, String>(0))?
+            .filter(|path| !path.as_ref()
+                .is_ok_and(|path| decode_path(path).exists()))
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(paths)
     }
 
     #[cfg(feature = "autotag")]
@@ -535,7 +1254,7 @@ impl Database {
 pub fn get_or_create_db(path: Option<&str>) -> Result<Database> {
     let conn = path.map_or_else(Connection::open_in_memory, Connection::open);
 
-    let db = conn.map(|conn| Database { conn })?;
+    let db = conn.map(|conn| Database { conn, root_query: None })?;
 
     db.init()?;
 
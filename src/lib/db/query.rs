@@ -474,7 +474,7 @@ impl Query {
 
     /// Runs the query on the provided database and returns the list of paths
     /// that match.
-    pub fn execute(self, db: &mut Database) -> Result<Vec<(String, u64)>> {
+    pub fn execute(self, db: &Database) -> Result<Vec<(String, u64)>> {
         let mut stmt = db.conn.prepare_cached(&self.sql)?;
         let params = rusqlite::params_from_iter(self.params);
 
@@ -485,6 +485,59 @@ impl Query {
         Ok(paths)
     }
 
+    /// Build a query that matches the paths that have every tag value pair
+    /// in tags. Values are matched exactly.
+    pub fn from_tags(tags: &[TagValuePair]) -> Result<Self> {
+        let mut tokens = Vec::new();
+
+        for (idx, tag) in tags.iter().enumerate() {
+            if idx != 0 {
+                tokens.push(Token::And);
+            }
+
+            tokens.push(Token::Tag(tag.tag.clone()));
+            if let Some(value) = &tag.value {
+                tokens.push(Token::StrictEquals);
+                tokens.push(Token::Value(value.clone()));
+            }
+        }
+
+        let raw = tags.iter()
+            .map(|tag| EscapedTagFormatter::from(tag).to_string())
+            .collect::<Vec<_>>()
+            .join(" and ");
+
+        let (sql, params) = to_sql(&tokens, true)?;
+
+        Ok(Self { _raw: raw, sql, params })
+    }
+
+    /// Build a query that only matches the paths that match both this query
+    /// and other.
+    pub fn and(self, other: &Query) -> Self {
+        let (subquery, other_params) = other.to_path_subquery();
+
+        let sql = format!(
+            "SELECT Path, TagMappingID FROM ({}) \
+            WHERE Path IN ({subquery}) \
+            ORDER BY TagMappingID",
+            self.sql
+        );
+        let params = self.params.into_iter()
+            .chain(other_params.iter().cloned())
+            .collect();
+
+        let raw = format!("({}) and ({})", self._raw, other._raw);
+
+        Self { _raw: raw, sql, params }
+    }
+
+    /// Returns SQL that selects only the paths matching the query, for use as
+    /// a subquery, along with its parameters.
+    pub fn to_path_subquery(&self) -> (String, &[String]) {
+        (format!("SELECT Path FROM ({})", self.sql), &self.params)
+    }
+
     // To build a query it is simply split into tokens and converted directly
     // to SQL. There is no need for proper parsing because the query language
     // has the same structure as the SQL query.
@@ -1,5 +1,7 @@
 //! Module to handle stored queries.
 
+use anyhow::{anyhow, bail};
+
 pub struct StoredQuery {
     pub name: String,
     pub query: String,
@@ -11,6 +13,49 @@ impl std::fmt::Display for StoredQuery {
     }
 }
 
+impl StoredQuery {
+    /// Returns true if s is written as a stored query rather than as a query
+    /// to run. `name=query` is read as the query `tag=value` unless the value
+    /// would contain a comparison itself, as in `crime=genre==crime`.
+    pub fn is_stored_query(s: &str) -> bool {
+        let displayed = s.strip_suffix(']')
+            .is_some_and(|s| s.contains(" @ ["));
+        let assigned = s.split_once('=')
+            .filter(|(name, query)| !name.is_empty()
+                && !query.starts_with('='))
+            .and_then(|(_, query)| query.split_whitespace().next())
+            .is_some_and(|value| value.contains(['=', '<', '>']));
+
+        displayed || assigned
+    }
+}
+
+/// Parses a stored query written as [`SanitisedStoredQuery`] displays it,
+/// `name @ [query]`, or as `name=query`.
+impl std::str::FromStr for StoredQuery {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, query) = s.strip_suffix(']')
+            .and_then(|s| s.split_once(" @ ["))
+            .map(|(name, query)| (unescape(name), unescape(query)))
+            .or_else(|| s.split_once('=')
+                .map(|(name, query)| (name.to_string(), query.to_string())))
+            .ok_or_else(|| anyhow!("\"{s}\" is not written as \
+                                    \"name @ [query]\" or \"name=query\"."))?;
+
+        let (name, query) = (name.trim(), query.trim());
+        if name.is_empty() || query.is_empty() {
+            bail!("\"{s}\" needs both a name and a query.");
+        }
+
+        Ok(Self { name: name.to_string(), query: query.to_string() })
+    }
+}
+
+/// Displays a stored query as a file name. A `/` cannot be part of a file
+/// name, so it is written as `%2F`, and `%` is written as `%25` so that the
+/// name can be parsed back into the same stored query.
 pub struct SanitisedStoredQuery<'a>(&'a StoredQuery);
 
 impl<'a> SanitisedStoredQuery<'a> {
@@ -25,18 +70,7 @@ impl<'a> SanitisedStoredQuery<'a> {
 
 impl<'a> std::fmt::Display for SanitisedStoredQuery<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} @ [", self.0.name)?;
-
-        for c in self.0.query.chars() {
-            match c {
-                '/' => write!(f, "_")?,
-                _ => write!(f, "{}", c)?,
-            }
-        }
-
-        write!(f, "]")?;
-
-        Ok(())
+        write!(f, "{} @ [{}]", escape(&self.0.name), escape(&self.0.query))
     }
 }
 
@@ -45,3 +79,60 @@ impl<'a> From<&'a StoredQuery> for SanitisedStoredQuery<'a> {
         Self(value)
     }
 }
+
+fn escape(s: &str) -> String {
+    s.replace('%', "%25").replace('/', "%2F")
+}
+
+// every % in an escaped string starts an escape, so %2F can be replaced
+// first without matching the end of a %25.
+fn unescape(s: &str) -> String {
+    s.replace("%2F", "/").replace("%25", "%")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SanitisedStoredQuery, StoredQuery};
+
+    #[test]
+    fn parse_test() {
+        let parse = |s: &str| s.parse::<StoredQuery>()
+            .map(|stored_query| (stored_query.name, stored_query.query));
+
+        assert_eq!(parse("crime @ [genre==crime]").unwrap(),
+            (String::from("crime"), String::from("genre==crime")));
+        assert_eq!(parse("crime=genre==crime or genre==thriller").unwrap(),
+            (String::from("crime"),
+             String::from("genre==crime or genre==thriller")));
+
+        assert!(parse("crime").is_err());
+        assert!(parse(" @ [genre==crime]").is_err());
+        assert!(parse("crime=").is_err());
+    }
+
+    #[test]
+    fn display_test() {
+        let stored_query = StoredQuery {
+            name: String::from("50%/50"),
+            query: String::from("path==/films/%2F"),
+        };
+
+        let display = SanitisedStoredQuery::from(&stored_query).to_string();
+        assert_eq!(display, "50%25%2F50 @ [path==%2Ffilms%2F%252F]");
+
+        let parsed = display.parse::<StoredQuery>().unwrap();
+        assert_eq!(parsed.name, stored_query.name);
+        assert_eq!(parsed.query, stored_query.query);
+    }
+
+    #[test]
+    fn is_stored_query_test() {
+        assert!(StoredQuery::is_stored_query("crime @ [genre==crime]"));
+        assert!(StoredQuery::is_stored_query("crime=genre==crime"));
+        assert!(StoredQuery::is_stored_query("old=year<1990"));
+        assert!(!StoredQuery::is_stored_query("genre==crime"));
+        assert!(!StoredQuery::is_stored_query("genre=crime"));
+        assert!(!StoredQuery::is_stored_query("genre=crime or year<1990"));
+        assert!(!StoredQuery::is_stored_query("genre"));
+    }
+}
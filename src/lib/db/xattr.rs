@@ -0,0 +1,319 @@
+//! Mirrors the tags in the database into the extended attributes of the real
+//! files and back again.
+//!
+//! Each tag on a path is stored in an attribute named after the tag with the
+//! [`XATTR_PREFIX`] prepended. The payload of the attribute is the list of
+//! values of the tag separated by [`XATTR_VALUE_SEPARATOR`], which is empty
+//! for tags without a value.
+//!
+//! Symlinks are never followed. The kernel does not allow user attributes on
+//! symlinks, so tagged symlinks are skipped.
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+
+use super::Database;
+use crate::path::{decode, decode_path, encode};
+
+/// Prefix of the extended attributes that hold the tags of a path. The rest
+/// of the attribute name is the name of the tag.
+pub const XATTR_PREFIX: &str = "user.tagfs.";
+
+/// Separates the values of a tag in the payload of an extended attribute.
+pub const XATTR_VALUE_SEPARATOR: char = '\n';
+
+/// Generate the extended attributes of a path from its tags as a map from
+/// attribute name to payload.
+pub fn xattrs(db: &Database, path: &str) -> Result<BTreeMap<String, Vec<u8>>>
+{
+    let mut xattrs: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+
+    for tag in db.tags(path)? {
+        let name = format!("{XATTR_PREFIX}{}", tag.tag.name);
+        let payload = xattrs.entry(name).or_default();
+
+        if let Some(value) = &tag.value {
+            if !payload.is_empty() {
+                payload.push(XATTR_VALUE_SEPARATOR as u8);
+            }
+            payload.extend_from_slice(value.as_bytes());
+        }
+    }
+
+    Ok(xattrs)
+}
+
+/// Parse the payload of an extended attribute into a list of values.
+pub fn parse_xattr_payload(payload: &str) -> Vec<&str> {
+    payload.split(XATTR_VALUE_SEPARATOR)
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// Write the tags of every path in the database to the extended attributes
+/// of the real files. Attributes of tags that have been removed are removed
+/// from the files. Paths that cannot be written to are skipped with a
+/// warning.
+///
+/// Paths without any tags are not in the database, so their attributes are
+/// only removed if they are under root. Otherwise they are kept and a
+/// rebuild would bring their tags back.
+pub fn sync_to_xattrs(db: &Database, root: Option<&str>) -> Result<()> {
+    let tagged = db.dump()?;
+    for path in tagged.keys() {
+        if let Err(e) = write_xattrs(db, path) {
+            warn!("skipping \"{path}\": {e:#}");
+        }
+    }
+
+    let Some(root) = root else { return Ok(()) };
+    let root = existing_root(root)?;
+
+    walk(root, &mut |path| {
+        if !tagged.contains_key(path) {
+            if let Err(e) = clear_xattrs(path) {
+                warn!("skipping \"{path}\": {e:#}");
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Write the tags of a single path to the extended attributes of the real
+/// file.
+fn write_xattrs(db: &Database, path: &str) -> Result<()> {
+    let xattrs = xattrs(db, path)?;
+
+    for name in list(path)? {
+        if !xattrs.contains_key(&name) {
+            remove(path, &name)?;
+        }
+    }
+
+    for (name, payload) in &xattrs {
+        set(path, name, payload)?;
+    }
+
+    Ok(())
+}
+
+/// Remove every tagfs extended attribute of a path.
+fn clear_xattrs(path: &str) -> Result<()> {
+    for name in list(path)? {
+        remove(path, &name)?;
+    }
+
+    Ok(())
+}
+
+/// Rebuild the tags of every path under root from the extended attributes of
+/// the real files. Each tag found in the attributes of a path replaces the
+/// values of that tag, tags that are only in the database are kept. Paths
+/// under root that no longer exist are removed from the database. Paths that
+/// cannot be read are kept and skipped with a warning.
+///
+/// Either the whole rebuild is applied or none of it is.
+pub fn sync_from_xattrs(db: &mut Database, root: &str) -> Result<()> {
+    let root = existing_root(root)?;
+
+    db.with_savepoint(|db| {
+        for path in db.paths_with_prefix(root)? {
+            // only paths that are known to be gone are removed, a dangling
+            // symlink still exists and a path that cannot be reached might.
+            match decode_path(&path).symlink_metadata() {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    db.untag_all(&path)?;
+                }
+                Err(e) => warn!("keeping \"{path}\": {e}"),
+            }
+        }
+
+        walk(root, &mut |path| {
+            match read_xattrs(path) {
+                Ok(tags) => {
+                    for (tag, payload) in tags {
+                        let values = parse_xattr_payload(&payload);
+                        db.set_tag(path, &tag, &values)?;
+                    }
+                }
+                Err(e) => warn!("skipping \"{path}\": {e:#}"),
+            }
+
+            Ok(())
+        })
+    })
+}
+
+/// Strip the trailing slashes of root and make sure it exists.
+fn existing_root(root: &str) -> Result<&str> {
+    let root = match root.trim_end_matches('/') {
+        "" => "/",
+        root => root,
+    };
+
+    // every path under a root that is missing, such as an unmounted drive,
+    // would be treated as untagged or removed.
+    if !decode_path(root).exists() {
+        bail!("\"{root}\" does not exist.");
+    }
+
+    Ok(root)
+}
+
+/// Call visit with a path and all paths below it. Children that cannot be
+/// listed are skipped with a warning.
+fn walk(path: &str, visit: &mut impl FnMut(&str) -> Result<()>)
+    -> Result<()>
+{
+    visit(path)?;
+
+    // symlinks are not followed to avoid walking in circles.
+    let real_path = decode_path(path);
+    if real_path.symlink_metadata().is_ok_and(|meta| meta.is_dir()) {
+        let children = match real_path.read_dir() {
+            Ok(children) => children,
+            Err(e) => {
+                warn!("skipping children of \"{path}\": {e}");
+                return Ok(());
+            }
+        };
+
+        for child in children {
+            match child {
+                Ok(child) => {
+                    let child = child.path();
+                    walk(&encode(child.as_os_str()), visit)?;
+                }
+                Err(e) => warn!("skipping child of \"{path}\": {e}"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the tags in the extended attributes of a path as pairs of tag name
+/// and payload.
+fn read_xattrs(path: &str) -> Result<Vec<(String, String)>> {
+    let mut tags = Vec::new();
+
+    for name in list(path)? {
+        let Some(tag) = name.strip_prefix(XATTR_PREFIX) else { continue };
+
+        let payload = String::from_utf8(get(path, &name)?).with_context(||
+            format!("tag \"{tag}\" on \"{path}\" is not valid UTF-8."))?;
+        tags.push((tag.to_string(), payload));
+    }
+
+    Ok(tags)
+}
+
+/// Convert a path from the database into a C string of the real path for use
+/// with libc.
+fn c_path(path: &str) -> Result<CString> {
+    CString::new(decode(path).as_bytes()).with_context(||
+        format!("\"{path}\" cannot contain a null byte."))
+}
+
+/// Convert a string into a C string for use with libc.
+fn c_string(s: &str) -> Result<CString> {
+    CString::new(s).with_context(||
+        format!("\"{s}\" cannot contain a null byte."))
+}
+
+/// List the names of the tagfs extended attributes of a path.
+fn list(path: &str) -> Result<Vec<String>> {
+    let c_path = c_path(path)?;
+
+    // the first call returns the size of the buffer required.
+    let size = unsafe {
+        libc::llistxattr(c_path.as_ptr(), std::ptr::null_mut(), 0)
+    };
+    if size < 0 {
+        return Err(std::io::Error::last_os_error()).with_context(||
+            format!("could not list extended attributes of \"{path}\"."));
+    }
+
+    let mut buf = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::llistxattr(c_path.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    };
+    if size < 0 {
+        return Err(std::io::Error::last_os_error()).with_context(||
+            format!("could not list extended attributes of \"{path}\"."));
+    }
+    buf.truncate(size as usize);
+
+    let names = buf.split(|&c| c == 0)
+        .filter_map(|name| std::str::from_utf8(name).ok())
+        .filter(|name| name.starts_with(XATTR_PREFIX))
+        .map(String::from)
+        .collect();
+
+    Ok(names)
+}
+
+/// Get the payload of an extended attribute of a path.
+fn get(path: &str, name: &str) -> Result<Vec<u8>> {
+    let (c_path, c_name) = (c_path(path)?, c_string(name)?);
+
+    let size = unsafe {
+        libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(),
+            std::ptr::null_mut(), 0)
+    };
+    if size < 0 {
+        return Err(std::io::Error::last_os_error()).with_context(||
+            format!("could not read \"{name}\" of \"{path}\"."));
+    }
+
+    let mut buf = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(),
+            buf.as_mut_ptr().cast(), buf.len())
+    };
+    if size < 0 {
+        return Err(std::io::Error::last_os_error()).with_context(||
+            format!("could not read \"{name}\" of \"{path}\"."));
+    }
+    buf.truncate(size as usize);
+
+    Ok(buf)
+}
+
+/// Set an extended attribute of a path.
+fn set(path: &str, name: &str, payload: &[u8]) -> Result<()> {
+    let (c_path, c_name) = (c_path(path)?, c_string(name)?);
+
+    let res = unsafe {
+        libc::lsetxattr(c_path.as_ptr(), c_name.as_ptr(),
+            payload.as_ptr().cast(), payload.len(), 0)
+    };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error()).with_context(||
+            format!("could not write \"{name}\" of \"{path}\"."));
+    }
+
+    Ok(())
+}
+
+/// Remove an extended attribute of a path.
+fn remove(path: &str, name: &str) -> Result<()> {
+    let (c_path, c_name) = (c_path(path)?, c_string(name)?);
+
+    let res = unsafe {
+        libc::lremovexattr(c_path.as_ptr(), c_name.as_ptr())
+    };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error()).with_context(||
+            format!("could not remove \"{name}\" of \"{path}\"."));
+    }
+
+    Ok(())
+}
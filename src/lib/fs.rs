@@ -9,25 +9,46 @@
 //! lookup(name: x, parent_inode: [`FUSE_ROOT_ID`]) -> [`fuser::FileAttr`]
 
 mod inode_generator;
+mod config;
+mod control;
+mod db_pool;
 mod entries;
+mod mounts;
+mod open_files;
+mod passthrough;
+mod workers;
 
-use inode_generator::INodeGenerator;
+pub use config::{DuplicateNames, MountConfig};
+pub use mounts::{mounts, unmount};
+
+use control::{ControlFile, CONTROL_DIR_NAME};
+use db_pool::DatabasePool;
 use entries::{Entries, EntryType};
+use open_files::OpenFiles;
+use workers::Workers;
 
 use std::{
-    borrow::Cow, collections::BTreeMap, fmt::Write, iter::Iterator, ffi::OsStr,
+    borrow::Cow, collections::{BTreeMap, HashSet}, fmt::Write,
+    iter::Iterator, ffi::OsStr, fs::File, os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf}, str::FromStr,
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, MutexGuard,
+    PoisonError, Weak}, time::{Duration, SystemTime},
 };
 
 use fuser::{
-    FileType, FUSE_ROOT_ID, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    ReplyOpen, Request
+    consts::FOPEN_DIRECT_IO, fuse_forget_one, FileAttr, FileType, FUSE_ROOT_ID,
+    Notifier, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use once_cell::sync::Lazy;
 
-use crate::db::Database;
-
-static TTL: std::time::Duration = std::time::Duration::from_secs(1);
+use crate::db::{
+    parse_xattr_payload, Database, EscapedTagFormatter, SanitisedStoredQuery,
+    StoredQuery, TagValuePair, XATTR_PREFIX,
+};
+use crate::error::TagFSErrorExt;
+use crate::path::{decode, decode_path, encode};
 
 /// Initialised to the time when the filesystem was mounted. Used as the *times
 /// on files and directories.
@@ -35,44 +56,345 @@ static MOUNT_TIME: Lazy<std::time::SystemTime> = Lazy::new(|| {
     std::time::SystemTime::now()
 });
 
-/// Filesystem struct that implements the [`fuser::Filesystem`] trait.
+/// State of the filesystem, shared by the threads that serve requests.
 ///
 /// Many of the methods for readdir take an optional [`fuser::ReplyDirectory`].
 /// This parameter is optional because it allows us to reuse these methods to
 /// create the internal entries even without a [`fuser::ReplyDirectory`]. This
 /// means we can call the readdir helper methods on lookup() calls so that we
 /// can use their side effects.
+///
+/// The entries and open files are locked for a single call at a time, so
+/// that no lock is held while the database is queried. Deadlocks are avoided
+/// by never holding two locks at once.
 #[derive(Debug)]
 struct TagFS {
-    entries: Entries,
-    db: Database,
+    entries: Mutex<Entries>,
+    db: DatabasePool,
+    open_files: Mutex<OpenFiles>,
+    config: MountConfig,
+    /// Version of the database when it was last checked for changes.
+    db_version: AtomicU64,
+    /// Absolute path the filesystem is mounted at.
+    mount_point: PathBuf,
 }
 
 impl TagFS {
-    pub fn new(db: Database) -> Self {
+    pub fn new(db: Database, config: MountConfig, mount_point: PathBuf)
+        -> Self
+    {
         Self {
-            db,
-            entries: Entries::new(),
+            db: DatabasePool::new(db),
+            entries: Mutex::new(Entries::new(&config)),
+            open_files: Mutex::new(OpenFiles::new()),
+            config,
+            db_version: AtomicU64::new(0),
+            mount_point,
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Entries> {
+        lock(&self.entries)
+    }
+
+    fn open_files(&self) -> MutexGuard<'_, OpenFiles> {
+        lock(&self.open_files)
+    }
+
+    /// Returns a connection to read from the database.
+    fn db(&self) -> db_pool::Connection<'_> {
+        self.db.reader()
+    }
+
+    /// Returns the connection to write to the database with.
+    fn db_writer(&self) -> MutexGuard<'_, Database> {
+        self.db.writer()
+    }
+
+    /// Helper function to find the real path an entry refers to. Only
+    /// [`EntryType::Link`], [`EntryType::AllTagsTerminal`] and
+    /// [`EntryType::Passthrough`] entries refer to a path.
+    fn entry_path(&self, inode: u64) -> Option<String> {
+        let entry_type = self.entries().get_type(inode)?;
+
+        match entry_type {
+            EntryType::Link => {
+                let tag_mapping_id = self.entries().get_link_target(inode)?;
+                self.db().get_path_from_id(tag_mapping_id).ok()
+            }
+            EntryType::AllTagsTerminal | EntryType::Passthrough => {
+                self.entries().get_path(inode).map(str::to_string)
+            }
+            _ => None,
+        }
+    }
+
+    /// Helper function to check whether an entry is shown as the real file or
+    /// directory it refers to, i.e. it is a link or a real file in
+    /// passthrough mode.
+    fn is_real(&self, inode: u64) -> bool {
+        self.config.passthrough && matches!(self.entries().get_type(inode),
+            Some(EntryType::Link | EntryType::Passthrough))
+    }
+
+    /// Helper function to check whether an entry is shown as a real
+    /// directory.
+    fn is_real_dir(&self, inode: u64) -> bool {
+        self.is_real(inode) && self.entries().get_attr(inode)
+            .is_some_and(|attr| attr.kind == FileType::Directory)
+    }
+
+    /// Helper function to give an entry the attributes of the real file at
+    /// path. Links to files that cannot be found are left as symlinks.
+    fn set_real_attr(&self, inode: u64, path: &str) {
+        match passthrough::attr(inode, path) {
+            Ok(attr) => self.entries().set_attr(inode, attr),
+            Err(e) => info!("could not find \"{path}\": {e:?}"),
+        }
+    }
+
+    /// Helper function to give a link the times and owner of the path it
+    /// refers to. The link itself is not followed.
+    fn set_target_attr(&self, inode: u64, path: &str) {
+        let metadata = match std::fs::symlink_metadata(decode_path(path)) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                info!("could not find \"{path}\": {e:?}");
+                return;
+            }
+        };
+
+        let Some(attr) = self.entries().get_attr(inode).copied() else {
+            return;
+        };
+
+        let target = passthrough::attr_from_metadata(inode, &metadata);
+        let attr = FileAttr {
+            atime: target.atime,
+            mtime: target.mtime,
+            ctime: target.ctime,
+            crtime: target.crtime,
+            uid: target.uid,
+            gid: target.gid,
+            ..attr
+        };
+        self.entries().set_attr(inode, attr);
+    }
+
+    /// Helper function to refresh the attributes of an entry that is shown as
+    /// a real file, or of a link that reports the attributes of its path.
+    /// They may have changed since the entry was created, but are only
+    /// checked once per TTL.
+    fn refresh_real_attr(&self, inode: u64) {
+        if self.entries().is_attr_fresh(inode, self.config.ttl) {
+            return;
+        }
+
+        if self.is_real(inode) {
+            if let Some(path) = self.entry_path(inode) {
+                self.set_real_attr(inode, &path);
+            }
+        } else if self.config.target_attrs
+            && matches!(self.entries().get_type(inode), Some(EntryType::Link))
+        {
+            if let Some(path) = self.entry_path(inode) {
+                self.set_target_attr(inode, &path);
+            }
+        }
+    }
+
+    /// Helper function to get the path of the tags file at inode, or the errno
+    /// to reply with when inode is not a tags file.
+    fn tags_file_path(&self, inode: u64) -> Result<String, libc::c_int> {
+        let entry_type = self.entries().get_type(inode);
+
+        match entry_type {
+            Some(EntryType::AllTagsTerminal) => {
+                self.entries().get_path(inode).map(str::to_string)
+                    .ok_or(libc::ENOENT)
+            }
+            Some(EntryType::Link) => Err(libc::EINVAL),
+            Some(_) => Err(libc::EISDIR),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    /// Helper function to generate the contents of a tags file in the
+    /// AllTags hierarchy. Values are escaped so that the contents can be
+    /// parsed back when the file is written.
+    fn tags_file_contents(&self, path: &str) -> String {
+        let mut buf = String::with_capacity(1024);
+
+        let tags = self.db().tags(path);
+        if let Ok(tags) = tags {
+            for tag in tags {
+                // unwrap is okay here, because we are writing to an in-memory
+                // string buffer.
+                writeln!(buf, "{}", EscapedTagFormatter::from(&tag)).unwrap();
+            }
+        }
+
+        buf
+    }
+
+    /// Helper function to give a tags file the size of its contents, so that
+    /// appending to it writes after the last tag. A file that is open for
+    /// writing has the size of its buffer.
+    fn refresh_tags_file_size(&self, inode: u64) {
+        let Ok(path) = self.tags_file_path(inode) else {
+            return;
+        };
+
+        let buffered = self.open_files().find_mut(inode)
+            .map(|file| file.contents.len());
+        let size = buffered.unwrap_or_else(||
+            self.tags_file_contents(&path).len()) as u64;
+
+        let Some(attr) = self.entries().get_attr(inode).copied() else {
+            return;
+        };
+        let attr = FileAttr { size, blocks: size.div_ceil(512), ..attr };
+        self.entries().set_attr(inode, attr);
+    }
+
+    /// Helper function to parse the contents written to a tags file and
+    /// replace the manual tags of its path. Each non-blank line is parsed as
+    /// a tag value pair. Returns an errno if the contents cannot be parsed or
+    /// applied, in which case the database is left unchanged.
+    fn save_tags_file(&self, path: &str, contents: &[u8])
+        -> Result<(), libc::c_int>
+    {
+        let contents = std::str::from_utf8(contents).map_err(|_| {
+            error!("contents of tags file for \"{path}\" are not UTF-8.");
+            libc::EINVAL
+        })?;
+
+        let tags = contents.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(TagValuePair::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                error!("could not parse tags file for \"{path}\": {e}");
+                libc::EINVAL
+            })?;
+
+        self.db_writer().replace_manual_tags(path, &tags).map_err(|e| {
+            error!("could not replace tags of \"{path}\": {e:?}");
+            libc::EINVAL
+        })
+    }
+
+    /// Helper function to generate the contents of a file in the control
+    /// directory without a query. Errors are logged and leave the file empty.
+    fn control_file_contents(&self, file: ControlFile) -> Vec<u8> {
+        file.contents(&mut self.db(), "").unwrap_or_else(|e| {
+            error!("could not generate \"{}\": {e:?}", file.name());
+            Vec::new()
+        })
+    }
+
+    /// Helper function to parse the query written to the query file in the
+    /// control directory. Returns an errno if the query is invalid.
+    fn parse_control_query<'a>(&self, contents: &'a [u8])
+        -> Result<&'a str, libc::c_int>
+    {
+        let query = std::str::from_utf8(contents).map_err(|_| {
+            error!("contents of the query file are not UTF-8.");
+            libc::EINVAL
+        })?.trim();
+
+        if !query.is_empty() {
+            if let Err(e) = self.db().query(query, false) {
+                error!("invalid query \"{query}\": {e:?}");
+                return Err(libc::EINVAL);
+            }
+        }
+
+        Ok(query)
+    }
+
+    /// Helper function to read the paths matching the query written to an
+    /// open handle of the query file. They are generated the first time the
+    /// handle is read after the query is written, so that every read of
+    /// them agrees. A handle without a query reads as empty.
+    fn query_file_results(&self, fh: u64) -> Result<Vec<u8>, libc::c_int> {
+        let contents = {
+            let mut open_files = self.open_files();
+            let Some(file) = open_files.get_mut(fh) else {
+                return Ok(Vec::new());
+            };
+            if let Some(results) = &file.results {
+                return Ok(results.clone());
+            }
+            file.contents.clone()
+        };
+
+        let query = self.parse_control_query(&contents)?;
+        let results = ControlFile::Query.contents(&mut self.db(), query)
+            .map_err(|e| {
+                error!("could not run query \"{query}\": {e:?}");
+                libc::EINVAL
+            })?;
+
+        if let Some(file) = self.open_files().get_mut(fh) {
+            file.results = Some(results.clone());
+        }
+        Ok(results)
+    }
+
+    /// Helper function to save a buffered tags file if it has changed since it
+    /// was last saved. The query of a query file is only checked, because it
+    /// belongs to its handle.
+    fn flush_open_file(&self, fh: u64) -> Result<(), libc::c_int> {
+        let (inode, contents) = {
+            let mut open_files = self.open_files();
+            let Some(file) = open_files.get_mut(fh) else {
+                return Ok(());
+            };
+
+            if !file.dirty {
+                return Ok(());
+            }
+            file.dirty = false;
+
+            (file.inode, file.contents.clone())
+        };
+
+        let control_file = self.entries().get_control_file(inode);
+        if control_file == Some(ControlFile::Query) {
+            return self.parse_control_query(&contents).map(|_| ());
         }
+
+        let Some(path) = self.entries().get_path(inode).map(str::to_string)
+        else {
+            error!("tags file inode {inode:#x?} no longer exists.");
+            return Err(libc::EIO);
+        };
+
+        self.save_tags_file(&path, &contents)
     }
 
     /// Helper function to reply with the root directory entries.
-    fn readdir_root(&mut self, offset: i64, mut reply: Option<ReplyDirectory>)
+    fn readdir_root(&self, offset: i64, mut reply: Option<ReplyDirectory>)
     {
-        let static_dirs = &[
-            self.entries.get_or_create_query_directory(),
-            self.entries.get_or_create_all_tags_dir(),
-        ];
+        let static_dirs = {
+            let mut entries = self.entries();
+            [
+                entries.get_or_create_query_directory(),
+                entries.get_or_create_all_tags_dir(),
+            ]
+        };
 
         if offset < static_dirs.len() as i64 {
             let offset_dirs = static_dirs.iter()
                 .enumerate().skip(offset as usize);
 
             for (idx, dir_inode) in offset_dirs {
+                let name = self.entries().get_name(*dir_inode)
+                    .unwrap_or_default().to_string();
                 let done = reply.as_mut().map_or(false, |reply|
                     reply.add(*dir_inode, (idx + 1) as i64,
-                        FileType::Directory,
-                        self.entries.get_name(*dir_inode)));
+                        FileType::Directory, name));
 
                 if done {
                     if let Some(reply) = reply { reply.ok() }
@@ -81,60 +403,197 @@ impl TagFS {
             }
         }
 
-        if let Ok(tags) = self.db.all_tags() {
-            for (idx, tag) in tags.iter().enumerate().skip(offset as usize) {
-                let child_inode = self.entries.get_or_create_tag_directory(
+        let tags = self.db().all_tags();
+        if let Ok(tags) = tags {
+            let mut listed = static_dirs.to_vec();
+
+            // the control directory is not listed, but must not be pruned.
+            let control_dir = self.entries().try_get_inode(FUSE_ROOT_ID,
+                CONTROL_DIR_NAME);
+            listed.extend(control_dir);
+
+            let tags = self.visible_root_tags(tags);
+            let tags_offset = (offset as usize).saturating_sub(
+                static_dirs.len());
+            for (idx, tag) in tags.iter().enumerate().skip(tags_offset) {
+                let child_inode = self.get_or_create_tag_directory(
                     FUSE_ROOT_ID, tag
                 );
+                listed.push(child_inode);
 
                 // we add two to the index to account for the query directory.
                 let done = reply.as_mut().map_or(false, |reply|
                     reply.add(child_inode,
                         (idx + 1 + static_dirs.len()) as i64,
-                        FileType::Directory, tag));
+                        FileType::Directory, decode(tag)));
 
                 if done { break; }
             }
+
+            self.prune_unlisted(FUSE_ROOT_ID, &listed, offset, &reply);
         }
         if let Some(reply) = reply { reply.ok() }
     }
 
+    /// Helper function to remove the tags that have the same name as the query
+    /// directory or the all tags directory, they cannot be shown in the root
+    /// directory unless one of those directories is renamed.
+    fn visible_root_tags(&self, mut tags: Vec<String>) -> Vec<String> {
+        tags.retain(|tag| !self.config.is_dir_name(tag));
+        tags
+    }
+
     /// Helper function to reply with the entries for a particular tag value
     /// pair.
-    fn readdir_files(&mut self, tag: &str, value: Option<&str>, inode: u64,
+    fn readdir_files(&self, tag: &str, value: Option<&str>, inode: u64,
                      offset: i64, mut reply: Option<ReplyDirectory>)
     {
-        if let Ok(children) = self.db.paths_with_tag(tag, value) {
+        let filter = self.entries().get_filter(inode).to_vec();
+
+        let children = self.db().paths_with_tag_matching(tag, value,
+                                                         &filter);
+        if let Ok(children) = children {
+            let nested_tags = self.nested_tags(inode, &children);
+            let mut listed = Vec::new();
+
+            let mut full = false;
+            for idx in (0..children.len()).skip(offset as usize) {
+                let (child_inode, display_name) =
+                    self.get_or_create_link(inode, &children, idx);
+                listed.push(child_inode);
+
+                let kind = self.entries().get_attr(child_inode)
+                    .map_or(FileType::Symlink, |attr| attr.kind);
+                full = reply.as_mut().map_or(false, |reply|
+                    reply.add(child_inode, (idx + 1) as i64, kind,
+                        decode(&display_name)));
+
+                if full { break; }
+            }
 
-            let children_offset = children.iter()
-                .enumerate().skip(offset as usize);
-            for (idx, (child, child_id)) in children_offset {
+            // the nested tag directories follow the paths.
+            let nested_offset = (offset as usize).saturating_sub(
+                children.len());
+            let nested_tags = nested_tags.iter()
+                .enumerate().skip(nested_offset)
+                .take_while(|_| !full);
+            for (idx, nested_tag) in nested_tags {
+                let child_inode = self.get_or_create_tag_directory(inode,
+                    nested_tag);
+                listed.push(child_inode);
+
+                let done = reply.as_mut().is_some_and(|reply|
+                    reply.add(child_inode, (children.len() + idx + 1) as i64,
+                        FileType::Directory, decode(nested_tag)));
 
-                let siblings = children.iter().map(|(child, _)| child);
-                let display_name = sanitise_path(child, idx, siblings);
+                if done { break; }
+            }
 
-                let child_inode = self.entries.try_get_link_inode(inode,
-                    display_name.as_ref(), *child_id)
-                        .unwrap_or_else(||
-                            self.entries.create_link(inode,
-                                display_name.as_ref(), *child_id,
-                                child.len() as u64));
+            self.prune_unlisted(inode, &listed, offset, &reply);
+        }
+        if let Some(reply) = reply { reply.ok() }
+    }
 
-                let done = reply.as_mut().map_or(false, |reply|
-                    reply.add(child_inode, (idx + 1) as i64,
-                        FileType::Symlink, display_name.as_str()));
+    /// Helper function to find the tags that can be nested in a directory to
+    /// narrow down its paths further. Tags already used by the directory or
+    /// the directories it is nested in are skipped, as are tags with the same
+    /// name as the link to one of the paths.
+    fn nested_tags(&self, inode: u64, paths: &[(String, u64)])
+        -> Vec<String>
+    {
+        let filter = self.entries().get_child_filter(inode);
+        if filter.len() >= self.config.max_depth {
+            return Vec::new();
+        }
 
-                if done { break; }
+        let tags = self.db().tags_matching(&filter).unwrap_or_default();
+
+        let link_names: HashSet<_> = paths.iter()
+            .map(|(path, tag_mapping_id)| sanitise_path(path, *tag_mapping_id,
+                paths.iter().map(|(path, id)| (path, *id)),
+                self.config.duplicate_names))
+            .collect();
+
+        tags.into_iter()
+            .filter(|tag| filter.iter().all(|pair| &pair.tag != tag))
+            .filter(|tag| !link_names.contains(tag))
+            .collect()
+    }
+
+    /// Helper function to find or create the directory of a tag. The tag is
+    /// only looked up in the database when the directory has to be created,
+    /// because its TagID is needed for the inode.
+    fn get_or_create_tag_directory(&self, parent: u64, name: &str) -> u64 {
+        let inode = self.entries().try_get_inode(parent, name);
+        if let Some(inode) = inode {
+            return inode;
+        }
+
+        let tag_id = self.db().get_tag(name).map_or(0, |tag| tag.id());
+        self.entries().get_or_create_tag_directory(parent, name, tag_id)
+    }
+
+    /// Helper function to find the target of a link to path, in a directory
+    /// dir_depth components below the root. The root of path is mapped and
+    /// the target is made relative to the directory if the config asks for
+    /// it.
+    fn link_target(&self, dir_depth: usize, path: &str) -> PathBuf {
+        let target = self.config.map_root(path);
+        let target = decode_path(&target);
+
+        if self.config.relative_links {
+            relative_target(&self.mount_point, dir_depth, &target)
+        } else {
+            target.into_owned()
+        }
+    }
+
+    /// Helper function to find or create the link entry for the path at idx in
+    /// a directory listing of paths. Returns the inode of the link and its
+    /// display name. In passthrough mode new links are given the attributes
+    /// of the real file.
+    fn get_or_create_link(&self, inode: u64, paths: &[(String, u64)],
+                          idx: usize) -> (u64, String)
+    {
+        let (path, tag_mapping_id) = &paths[idx];
+
+        let siblings = paths.iter().map(|(path, id)| (path, *id));
+        let display_name = sanitise_path(path, *tag_mapping_id, siblings,
+            self.config.duplicate_names);
+
+        let dir_depth = self.entries().get_depth(inode);
+        let target_len = self.link_target(dir_depth, path).as_os_str().len();
+
+        // the link is created under the same lock it is looked up with, so
+        // that two threads listing the same directory agree on its inode.
+        let child_inode = {
+            let mut entries = self.entries();
+            let child_inode = entries.try_get_link_inode(inode,
+                display_name.as_ref(), *tag_mapping_id);
+            if let Some(child_inode) = child_inode {
+                return (child_inode, display_name);
             }
+
+            entries.create_link(inode, display_name.as_ref(),
+                *tag_mapping_id, target_len as u64)
+        };
+
+        if self.config.passthrough {
+            self.set_real_attr(child_inode, path);
         }
-        if let Some(reply) = reply { reply.ok() }
+
+        (child_inode, display_name)
     }
 
     /// Helper function to reply with all the values for a particular tag.
-    fn readdir_values(&mut self, tag: &str, inode: u64, offset: i64,
+    fn readdir_values(&self, tag: &str, inode: u64, offset: i64,
                       mut reply: Option<ReplyDirectory>)
     {
-        if let Ok(children) = self.db.values(tag) {
+        let filter = self.entries().get_filter(inode).to_vec();
+
+        let children = self.db().values_matching(tag, &filter);
+        if let Ok(children) = children {
+            let mut listed = Vec::new();
 
             let children_offset = children.iter()
                 .enumerate().skip(offset as usize);
@@ -142,50 +601,257 @@ impl TagFS {
 
                 let display_name = sanitise_value(child);
 
-                let child_inode = self.entries.try_get_inode(inode,
-                    display_name.as_ref())
-                        .unwrap_or_else(||
-                            self.entries.get_or_create_value_directory(inode,
-                                display_name.as_ref(), child));
+                let child_inode = self.entries().try_get_inode(inode,
+                    display_name.as_ref());
+                let child_inode = child_inode.unwrap_or_else(||
+                    self.entries().get_or_create_value_directory(inode,
+                        display_name.as_ref(), child));
+                listed.push(child_inode);
 
                 let done = reply.as_mut().map_or(false, |reply|
                     reply.add(child_inode, (idx + 1) as i64,
-                        FileType::Directory, display_name.as_str()));
+                        FileType::Directory, decode(&display_name)));
 
                 if done { break; }
             }
+
+            self.prune_unlisted(inode, &listed, offset, &reply);
+        }
+        if let Some(reply) = reply { reply.ok() }
+    }
+
+    /// Helper function to remove the tag value pair of a directory from the
+    /// path of a link within it.
+    fn untag_link(&self, parent: u64, name: &str, inode: u64)
+        -> Result<(), libc::c_int>
+    {
+        // links can only be removed from the tag hierarchy, removing a link
+        // from a query result has no obvious meaning.
+        let Some((tag, value)) = self.dir_tag_value(parent) else {
+            return Err(libc::EPERM);
+        };
+
+        let tag_mapping_id = self.entries().get_link_target(inode);
+        let path = tag_mapping_id.and_then(|tag_mapping_id|
+            self.db().get_path_from_id(tag_mapping_id).ok());
+
+        let Some(path) = path else {
+            error!("could not find link target for inode: {inode:#x?}.");
+            return Err(libc::ENOENT);
+        };
+
+        if let Err(e) = self.db_writer().untag(&path, &tag, value.as_deref()) {
+            error!("could not untag \"{path}\": {e:?}");
+            return Err(libc::ENOENT);
+        }
+
+        self.entries().remove_child(parent, name);
+        self.prune_tag_dirs(&tag, value.as_deref());
+
+        Ok(())
+    }
+
+    /// Helper function to open the real file an entry refers to, in
+    /// passthrough mode. Real files can only be read.
+    fn open_real(&self, inode: u64, flags: i32, reply: ReplyOpen) {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let Some(path) = self.entry_path(inode) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        match File::open(decode_path(&path)) {
+            Ok(file) => {
+                let fh = self.open_files().open_real(file);
+                reply.opened(fh, 0);
+            }
+            Err(e) => {
+                error!("could not open \"{path}\": {e:?}");
+                reply.error(passthrough::errno(&e));
+            }
+        }
+    }
+
+    /// Helper function to reply with the children of a real directory, in
+    /// passthrough mode.
+    fn readdir_real(&self, inode: u64, offset: i64,
+                    mut reply: Option<ReplyDirectory>)
+    {
+        let Some(path) = self.entry_path(inode) else {
+            if let Some(reply) = reply { reply.error(libc::ENOENT) }
+            return;
+        };
+
+        let children = match passthrough::children(&path) {
+            Ok(children) => children,
+            Err(e) => {
+                error!("could not list \"{path}\": {e:?}");
+                if let Some(reply) = reply {
+                    reply.error(passthrough::errno(&e));
+                }
+                return;
+            }
+        };
+
+        let mut listed = Vec::new();
+
+        let children_offset = children.iter()
+            .enumerate().skip(offset as usize);
+        for (idx, name) in children_offset {
+            let child_path = camino::Utf8Path::new(&path).join(name);
+            let Ok(attr) = passthrough::attr(0, child_path.as_str()) else {
+                continue;
+            };
+
+            let child_inode = self.entries().get_or_create_passthrough(inode,
+                name, child_path.as_str(), attr);
+            listed.push(child_inode);
+
+            let done = reply.as_mut().is_some_and(|reply|
+                reply.add(child_inode, (idx + 1) as i64, attr.kind,
+                    decode(name)));
+
+            if done { break; }
         }
+
+        self.prune_unlisted(inode, &listed, offset, &reply);
         if let Some(reply) = reply { reply.ok() }
     }
 
+    /// Helper function to open a file in the control directory. Only the
+    /// query file can be opened for writing. Each handle has its own query,
+    /// which is read back as the paths that match it from the same handle.
+    fn open_control_file(&self, inode: u64, file: ControlFile, flags: i32,
+                         reply: ReplyOpen)
+    {
+        if flags & libc::O_ACCMODE == libc::O_RDONLY {
+            reply.opened(0, FOPEN_DIRECT_IO);
+            return;
+        }
+
+        if !file.is_writable() {
+            reply.error(libc::EACCES);
+            return;
+        }
+
+        let fh = self.open_files().open(inode, Vec::new());
+        reply.opened(fh, FOPEN_DIRECT_IO);
+    }
+
+    /// Helper function to prune the children of a directory that were not
+    /// listed, because they no longer exist in the database. Only a listing
+    /// from the start without a reply is complete, otherwise nothing is
+    /// pruned.
+    fn prune_unlisted(&self, inode: u64, listed: &[u64], offset: i64,
+                      reply: &Option<ReplyDirectory>)
+    {
+        if offset == 0 && reply.is_none() {
+            let version = self.db_version.load(Ordering::Relaxed);
+            let mut entries = self.entries();
+            entries.retain_children(inode, listed);
+            entries.set_listed_version(inode, version);
+        }
+    }
+
+    /// Helper function to check whether the database has changed since it was
+    /// last checked. Directories listed before the change are listed again
+    /// when their children are next looked up.
+    fn check_db_version(&self) {
+        match self.db.version() {
+            Ok(version) => {
+                let old_version = self.db_version.swap(version,
+                    Ordering::Relaxed);
+                if version != old_version {
+                    info!("database changed, refreshing entries.");
+                }
+            }
+            Err(e) => error!("could not check the database version: {e:?}"),
+        }
+    }
+
+    /// Helper function to tell the kernel to forget every entry it may have
+    /// cached, so that changes to the database show up straight away. The
+    /// entries are looked up again the next time they are used.
+    fn invalidate_entries(&self, notifier: &Notifier) {
+        // the entries are not locked while notifying, because the kernel
+        // may wait for requests that need them.
+        let children = self.entries().children();
+
+        let mut parents = HashSet::new();
+        for (parent, name) in children {
+            ignore_uncached(notifier.inval_entry(parent, &decode(&name)));
+            parents.insert(parent);
+        }
+        for parent in parents {
+            ignore_uncached(notifier.inval_inode(parent, 0, 0));
+        }
+    }
+
+    /// Helper function to reply with the attributes of an entry. The kernel
+    /// holds a lookup on the inode until it is forgotten.
+    fn reply_entry(&self, inode: u64, reply: ReplyEntry) {
+        self.refresh_real_attr(inode);
+        self.refresh_tags_file_size(inode);
+
+        // the lookup is counted before replying, because the kernel may
+        // forget it on another thread as soon as it has the reply.
+        let attr = {
+            let mut entries = self.entries();
+            let attr = entries.get_attr(inode).copied();
+            if attr.is_some() {
+                entries.lookup(inode);
+            }
+            attr
+        };
+
+        if let Some(attr) = attr {
+            reply.entry(&self.config.ttl, &attr, 0);
+        } else {
+            error!("tried to reply with non existent inode: {inode:#x?}.");
+            reply.error(libc::ENOENT);
+        }
+    }
+
     /// Helper function to look up the root inode and create its children if
     /// necessary. The reason we return the inode of the child is so that we
     /// can pre-emptively call readdir with that inode to 'preload' the
     /// entries.
-    fn lookup_root_child(&mut self, parent: u64, name: &str, reply: ReplyEntry)
+    fn lookup_root_child(&self, parent: u64, name: &str, reply: ReplyEntry)
         -> Option<u64>
     {
-        let matches_tag = self.db.all_tags()
-            .map_or(false, |tags| tags.iter().any(|tag| tag == name));
+        let matches_tag = !self.config.is_dir_name(name)
+            && self.db().all_tags()
+                .map_or(false, |tags| tags.iter().any(|tag| tag == name));
 
         // the children of the root directory are either the name of a tag, or
         // the query directory.
         if matches_tag {
-            let inode = self.entries.get_or_create_tag_directory(parent, name);
-            reply.entry(&TTL, self.entries.get_attr(inode), 0);
+            let inode = self.get_or_create_tag_directory(parent, name);
+            self.reply_entry(inode, reply);
             Some(inode)
 
-        } else {
-            let query_dir_inode = self.entries.get_or_create_query_directory();
-            let all_tags_dir_inode = self.entries.get_or_create_all_tags_dir();
+        } else if name == CONTROL_DIR_NAME {
+            let inode = self.entries().get_or_create_control_dir();
+            self.reply_entry(inode, reply);
+            Some(inode)
 
-            if name == self.entries.get_name(query_dir_inode) {
-                reply.entry(&TTL, self.entries.get_attr(query_dir_inode), 0);
+        } else {
+            let (query_dir_inode, all_tags_dir_inode) = {
+                let mut entries = self.entries();
+                (entries.get_or_create_query_directory(),
+                    entries.get_or_create_all_tags_dir())
+            };
+
+            if name == self.config.query_dir_name {
+                self.reply_entry(query_dir_inode, reply);
                 Some(query_dir_inode)
 
-            } else if name == self.entries.get_name(all_tags_dir_inode) {
-                reply.entry(&TTL,
-                    self.entries.get_attr(all_tags_dir_inode), 0);
+            } else if name == self.config.all_tags_dir_name {
+                self.reply_entry(all_tags_dir_inode, reply);
                 Some(all_tags_dir_inode)
 
             } else {
@@ -196,82 +862,176 @@ impl TagFS {
     }
 
     /// Helper function to reply with all the paths for a particular query.
-    fn readdir_query(&mut self, inode: u64, offset: i64,
+    fn readdir_query(&self, inode: u64, offset: i64,
                      mut reply: Option<ReplyDirectory>)
     {
-        let query = self.entries.get_query(inode);
+        let query = self.entries().get_query(inode).unwrap_or_default()
+            .to_string();
         // the else case _should_ never happen because we have
         // already rejected any invalid queries.
-        if let Ok(paths) = self.db.query(query, false) {
-
-            let paths_offset = paths.iter().enumerate().skip(offset as usize);
-            for (idx, (child, child_id)) in paths_offset {
+        let paths = self.db().query(&query, false);
+        if let Ok(paths) = paths {
+            let mut listed = Vec::new();
 
-                let siblings = paths.iter().map(|(child, _)| child);
-                let display_name = sanitise_path(child, idx, siblings);
-
-                let child_inode = self.entries.try_get_link_inode(inode,
-                    display_name.as_ref(), *child_id)
-                        .unwrap_or_else(||
-                            self.entries.create_link(
-                                inode, display_name.as_ref(),
-                                *child_id, child.len() as u64
-                            ));
+            for idx in (0..paths.len()).skip(offset as usize) {
+                let (child_inode, display_name) =
+                    self.get_or_create_link(inode, &paths, idx);
+                listed.push(child_inode);
 
+                let kind = self.entries().get_attr(child_inode)
+                    .map_or(FileType::Symlink, |attr| attr.kind);
                 let done = reply.as_mut().map_or(false, |reply|
-                    reply.add(child_inode, (idx + 1) as i64,
-                        FileType::Symlink, display_name.as_str()));
+                    reply.add(child_inode, (idx + 1) as i64, kind,
+                        decode(&display_name)));
 
                 if done { break; }
             }
+
+            self.prune_unlisted(inode, &listed, offset, &reply);
         }
 
         if let Some(reply) = reply { reply.ok() }
     }
 
-    fn readdir_query_dir(&mut self, offset: i64,
+    fn readdir_query_dir(&self, offset: i64,
                          mut reply: Option<ReplyDirectory>)
     {
-        let Ok(stored_queries) = self.db.stored_queries() else {
+        let Ok(stored_queries) = self.db().stored_queries() else {
             if let Some(reply) = reply { reply.ok() }
             return;
         };
 
         let stored_queries_offset = stored_queries.iter()
-            .map(crate::db::SanitisedStoredQuery::from)
+            .map(SanitisedStoredQuery::from)
             .enumerate().skip(offset as usize);
+        let mut listed = Vec::new();
 
         for (idx, stored_query) in stored_queries_offset {
             let stored_query_display = stored_query.to_string();
-            let child_inode = self.entries.get_or_create_query_result_dir(
+            let child_inode = self.entries().get_or_create_query_result_dir(
                 &stored_query.query(), &stored_query_display);
+            listed.push(child_inode);
 
             let done = reply.as_mut().map_or(false, |reply|
                 reply.add(child_inode, (idx + 1) as i64,
-                    FileType::Symlink, &stored_query_display));
+                    FileType::Directory, decode(&stored_query_display)));
+
+            if done { break; }
+        }
+
+        // ad-hoc queries are pruned along with deleted stored queries, they
+        // are recreated when they are looked up again. The other names stored
+        // queries were created or looked up with are kept, so that they keep
+        // the inode the kernel knows them by.
+        let queries: HashSet<_> = stored_queries.iter()
+            .map(|stored_query| stored_query.query.as_str())
+            .collect();
+        let query_dir_inode = self.entries().get_or_create_query_directory();
+        {
+            let entries = self.entries();
+            listed.extend(entries.child_inodes(query_dir_inode).into_iter()
+                .filter(|inode| entries.get_query(*inode)
+                    .is_some_and(|query| queries.contains(query))));
+        }
+        self.prune_unlisted(query_dir_inode, &listed, offset, &reply);
+
+        if let Some(reply) = reply { reply.ok() }
+    }
+
+    /// Helper function to store the query written as name in the query
+    /// directory, see [`StoredQuery`] for the formats it can be written in.
+    /// Returns the inode of its directory.
+    fn create_stored_query(&self, name: &str) -> Result<u64, libc::c_int> {
+        let stored_query = name.parse::<StoredQuery>().map_err(|e| {
+            error!("could not create stored query: {e:?}");
+            libc::EINVAL
+        })?;
+
+        if let Err(e) = self.db().query(&stored_query.query, false) {
+            error!("could not create stored query \"{name}\": {e:?}");
+            return Err(libc::EINVAL);
+        }
+
+        let res = self.db_writer().create_stored_query(&stored_query.name,
+            &stored_query.query);
+        if res.is_sql_unique_cons_err() {
+            return Err(libc::EEXIST);
+        }
+        if let Err(e) = res {
+            error!("could not create stored query \"{name}\": {e:?}");
+            return Err(libc::EIO);
+        }
+
+        // the entry is created under the name it was created with, the same
+        // as a lookup of that name does, so both return the same inode.
+        Ok(self.entries().get_or_create_query_result_dir(&stored_query.query,
+            name))
+    }
+
+    /// Helper function to find the stored query that name refers to in the
+    /// query directory, either by the name it is listed as, by its own name
+    /// or by the name it was created with, such as "name=query".
+    fn find_stored_query(&self, name: &str) -> Option<StoredQuery> {
+        let created = StoredQuery::is_stored_query(name)
+            .then(|| name.parse::<StoredQuery>().ok())
+            .flatten();
+
+        self.db().stored_queries().unwrap_or_default().into_iter()
+            .find(|stored_query| stored_query.name == name
+                || created.as_ref().is_some_and(|created|
+                    created.name == stored_query.name
+                        && created.query == stored_query.query)
+                || {
+                    let display = SanitisedStoredQuery::from(stored_query);
+                    display.to_string() == name
+                })
+    }
+
+    /// Helper function to reply with the files in the control directory.
+    fn readdir_control(&self, inode: u64, offset: i64,
+                       mut reply: Option<ReplyDirectory>)
+    {
+        let files = ControlFile::ALL.iter().enumerate().skip(offset as usize);
+        let mut listed = Vec::new();
+
+        for (idx, file) in files {
+            let child_inode = self.entries().get_or_create_control_file(
+                inode, *file);
+            listed.push(child_inode);
+
+            let done = reply.as_mut().is_some_and(|reply|
+                reply.add(child_inode, (idx + 1) as i64,
+                    FileType::RegularFile, file.name()));
 
             if done { break; }
         }
 
+        self.prune_unlisted(inode, &listed, offset, &reply);
         if let Some(reply) = reply { reply.ok() }
     }
 
     /// Read a directory in the AllTags hierarchy. Mirrors the user's paths and
     /// terminates them with a file containing the tags on that path.
-    fn readdir_all_tags(&mut self, inode: u64, offset: i64,
+    fn readdir_all_tags(&self, inode: u64, offset: i64,
                         mut reply: Option<ReplyDirectory>)
     {
-        let path = self.entries.get_path(inode);
+        let path = self.entries().get_path(inode).map(str::to_string);
+        let Some(path) = path else {
+            error!("tried to readdir non AllTags entry: {inode:#x?}.");
+            if let Some(reply) = reply { reply.error(libc::ENOENT) }
+            return;
+        };
 
         let mut uniq_names: BTreeMap<Cow<str>, _> = BTreeMap::new();
 
-        if let Ok(children) = self.db.paths_with_prefix(path) {
+        let children = self.db().paths_with_prefix(&path);
+        if let Ok(children) = children {
             for child in &children {
-                let Some(child_stripped) = child.strip_prefix(path) else {
+                let Some(child_stripped) = child.strip_prefix(path.as_str())
+                else {
                     error!("programming error - path: \"{child}\" must have \
                             prefix: \"{path}\".");
-                    panic!("programming error - path: \"{child}\" must have \
-                            prefix: \"{path}\".");
+                    continue;
                 };
 
                 if let Some(split_point) = child_stripped.find('/') {
@@ -292,276 +1052,1396 @@ impl TagFS {
             let uniq_names_offset = uniq_names.iter()
                 .enumerate()
                 .skip(offset as usize);
+            let mut listed = Vec::new();
 
             for (idx, (name, (path, kind))) in uniq_names_offset {
                 let child_inode = if *kind == FileType::Directory {
-                    self.entries.get_or_create_all_tags_intermediate(inode,
+                    self.entries().get_or_create_all_tags_intermediate(inode,
                         name, path)
                 } else {
-                    self.entries.get_or_create_all_tags_terminal(inode,
+                    self.entries().get_or_create_all_tags_terminal(inode,
                         name, path)
                 };
+                listed.push(child_inode);
 
                 let done = reply.as_mut().map_or(false, |reply|
                     reply.add(child_inode, (idx + 1) as i64,
-                        *kind, name.as_ref()));
+                        *kind, decode(name)));
 
                 if done { break; }
             }
+
+            self.prune_unlisted(inode, &listed, offset, &reply);
         }
 
         if let Some(reply) = reply { reply.ok() }
     }
 
-    /// Helper function that is called by both readdir and lookup.
-    ///
-    /// Creates the child inodes of a particular directory.
-    ///
-    /// Can be called with reply as None to do a 'fake' readdir for its side
-    /// effects only.
-    fn readdir_helper(&mut self, inode: u64, offset: i64,
-                      reply: Option<ReplyDirectory>)
-    {
-        let attr = self.entries.get_attr(inode);
-
-        if attr.kind == FileType::Directory {
-            let name = self.entries.get_name(inode);
-
-            match self.entries.get_type(inode) {
-                EntryType::TagDir => {
-                    if let Some(tag) = self.db.get_tag(name) {
-                        if tag.takes_value {
-                            self.readdir_values(&tag.name, inode,
-                                offset, reply);
-                        } else {
-                            self.readdir_files(&tag.name, None,
-                                inode, offset, reply);
-                        }
-                    } else {
-                        panic!("programming error: tried to readdir for a \
-                                directory without a tag in the database.");
-                    }
-                }
-                EntryType::ValueDir => {
-                    let tag_name = self.entries.get_parent_tag(inode)
-                        .to_string();
-                    let value = self.entries.get_tag_value(inode).to_string();
-                    self.readdir_files(&tag_name, Some(&value),
-                        inode, offset, reply);
-                }
+    /// Returns the tag and optional value represented by a
+    /// [`EntryType::TagDir`] or [`EntryType::ValueDir`]. Returns None for any
+    /// other type of entry and for nested directories, which are a read only
+    /// view of a query.
+    fn dir_tag_value(&self, inode: u64) -> Option<(String, Option<String>)> {
+        let entries = self.entries();
 
-                // query dir contains stored queries and nothing else.
-                EntryType::QueryDir => {
-                    self.readdir_query_dir(offset, reply);
+        if entries.is_nested(inode) {
+            return None;
+        }
+
+        match entries.get_type(inode)? {
+            EntryType::TagDir => {
+                Some((entries.get_name(inode)?.to_string(), None))
+            }
+            EntryType::ValueDir => {
+                Some((entries.get_parent_tag(inode)?.to_string(),
+                    Some(entries.get_tag_value(inode)?.to_string())))
+            }
+            _ => None,
+        }
+    }
+
+    /// Helper function to remove the directories of a tag value pair from the
+    /// entries once it no longer has any paths in the database. The entries
+    /// are only detached from their parent because the kernel may still hold
+    /// a reference to their inodes.
+    fn prune_tag_dirs(&self, tag: &str, value: Option<&str>) {
+        let Some(tag_inode) = self.entries().try_get_inode(FUSE_ROOT_ID, tag)
+            else { return };
+
+        if let Some(value) = value {
+            let value_unused = self.db().paths_with_tag(tag, Some(value))
+                .is_ok_and(|paths| paths.is_empty());
+
+            if value_unused {
+                self.entries().remove_child(tag_inode, &sanitise_value(value));
+            }
+        }
+
+        if self.db().get_tag(tag).is_none() {
+            self.entries().remove_child(FUSE_ROOT_ID, tag);
+        }
+    }
+
+    /// Helper function to find the name a link to path would be shown under
+    /// in the tag or value directory at inode, once path is tagged with the
+    /// tag value pair of the directory. The id the new mapping will be given
+    /// decides how it is told apart from paths with the same name, which
+    /// takes into account that the mapping with the id moved is deleted
+    /// first when a link is moved. Returns None if inode is not a tag or value
+    /// directory or path is already in it.
+    fn new_link_name(&self, inode: u64, path: &str, moved: Option<u64>)
+        -> Option<String>
+    {
+        let (tag, value) = self.dir_tag_value(inode)?;
+
+        let (paths, tag_mapping_id) = {
+            let mut db = self.db();
+            let paths = db.paths_with_tag(&tag, value.as_deref())
+                .unwrap_or_default();
+            (paths, db.next_tag_mapping_id(moved).ok()?)
+        };
+        if paths.iter().any(|(child, _)| child == path) {
+            return None;
+        }
+
+        let siblings = paths.iter().map(|(child, id)| (child.as_str(), *id))
+            .chain(std::iter::once((path, tag_mapping_id)));
+        Some(sanitise_path(path, tag_mapping_id, siblings,
+            self.config.duplicate_names))
+    }
+
+    /// Helper function to move the target of a link from the tag value pair of
+    /// one directory to that of another. On success the link entry is
+    /// updated to refer to the new mapping.
+    fn retag_link(&self, inode: u64, parent: u64, new_parent: u64)
+        -> anyhow::Result<()>
+    {
+        let (Some((tag, value)), Some((new_tag, new_value)))
+            = (self.dir_tag_value(parent), self.dir_tag_value(new_parent))
+        else {
+            anyhow::bail!("links can only be moved between tag directories.");
+        };
+
+        let Some(tag_mapping_id) = self.entries().get_link_target(inode) else {
+            anyhow::bail!("inode {inode:#x?} is not a link.");
+        };
+        let path = self.db().get_path_from_id(tag_mapping_id)?;
+
+        self.db_writer().retag(&path, &tag, value.as_deref(), &new_tag,
+            new_value.as_deref())?;
+
+        let new_tag_mapping_id = self.db()
+            .paths_with_tag(&new_tag, new_value.as_deref())?
+            .into_iter()
+            .find_map(|(child, tag_mapping_id)|
+                (child == path).then_some(tag_mapping_id));
+
+        if let Some(tag_mapping_id) = new_tag_mapping_id {
+            self.entries().set_link_target(inode, tag_mapping_id);
+        }
+        self.prune_tag_dirs(&tag, value.as_deref());
+
+        Ok(())
+    }
+
+    /// Helper function that is called by both readdir and lookup.
+    ///
+    /// Creates the child inodes of a particular directory.
+    ///
+    /// Can be called with reply as None to do a 'fake' readdir for its side
+    /// effects only.
+    fn readdir_helper(&self, inode: u64, offset: i64,
+                      reply: Option<ReplyDirectory>)
+    {
+        let Some(entry_type) = self.entries().get_type(inode) else {
+            error!("tried to readdir non existent inode: {inode:#x?}.");
+            if let Some(reply) = reply { reply.error(libc::ENOENT) }
+            return;
+        };
+
+        match entry_type {
+            EntryType::TagDir => {
+                let name = self.entries().get_name(inode).unwrap_or_default()
+                    .to_string();
+
+                let tag = self.db().get_tag(&name);
+                if let Some(tag) = tag {
+                    if tag.takes_value {
+                        self.readdir_values(&tag.name, inode, offset, reply);
+                    } else {
+                        self.readdir_files(&tag.name, None, inode, offset,
+                            reply);
+                    }
+                } else {
+                    // the tag may have been removed from the database since
+                    // the directory was created.
+                    error!("tried to readdir tag directory \"{name}\" \
+                            without a tag in the database.");
+                    if let Some(reply) = reply { reply.error(libc::ENOENT) }
+                }
+            }
+            EntryType::ValueDir => {
+                let tag_name = self.entries().get_parent_tag(inode)
+                    .unwrap_or_default().to_string();
+                let value = self.entries().get_tag_value(inode)
+                    .unwrap_or_default().to_string();
+                self.readdir_files(&tag_name, Some(&value),
+                    inode, offset, reply);
+            }
+
+            // query dir contains stored queries and nothing else.
+            EntryType::QueryDir => {
+                self.readdir_query_dir(offset, reply);
+            }
+
+            EntryType::QueryResultDir => {
+                self.readdir_query(inode, offset, reply);
+            }
+
+            EntryType::AllTagsDir | EntryType::AllTagsIntermediate => {
+                self.readdir_all_tags(inode, offset, reply);
+            }
+
+            EntryType::Root => {
+                self.readdir_root(offset, reply);
+            }
+
+            EntryType::ControlDir => {
+                self.readdir_control(inode, offset, reply);
+            }
+
+            EntryType::Link | EntryType::Passthrough
+                if self.is_real_dir(inode) =>
+            {
+                self.readdir_real(inode, offset, reply);
+            }
+
+            EntryType::Link | EntryType::AllTagsTerminal
+                | EntryType::Passthrough | EntryType::ControlFile =>
+            {
+                if let Some(reply) = reply { reply.error(libc::ENOTDIR) }
+            }
+        }
+    }
+}
+
+// the FUSE operations, which the Dispatcher calls on a worker thread.
+impl TagFS {
+
+    // saves the tags files that are still open when the filesystem is
+    // unmounted, because they will not be flushed otherwise.
+    fn destroy(&self) {
+        info!("destroy()");
+
+        let handles = self.open_files().handles();
+        for fh in handles {
+            if let Err(errno) = self.flush_open_file(fh) {
+                error!("could not save open file {fh}: errno {errno}.");
+            }
+            self.open_files().close(fh);
+        }
+    }
+
+    // look up inode and get its attrs.
+    fn getattr(&self, inode: u64, reply: ReplyAttr) {
+        info!("getattr(inode: {inode:#x?})");
+
+        self.refresh_real_attr(inode);
+        self.refresh_tags_file_size(inode);
+
+        let attr = self.entries().get_attr(inode).copied();
+        if let Some(attr) = attr {
+            reply.attr(&self.config.ttl, &attr);
+        } else {
+            error!("tried to getattr non existent inode: {inode:#x?}.");
+            reply.error(libc::ENOENT);
+        }
+    }
+
+    // reports the tagged paths as the inodes of the filesystem, all of them
+    // in use, so that df -i shows how many paths are tagged. The mount itself
+    // takes up no space.
+    fn statfs(&self, reply: ReplyStatfs) {
+        info!("statfs()");
+
+        let files = self.db().path_count().unwrap_or_else(|e| {
+            error!("could not count the tagged paths: {e:?}");
+            0
+        });
+        let ffree = 0;
+
+        let block_size = 512;
+        let max_name_len = 255;
+        reply.statfs(0, 0, 0, files, ffree, block_size, max_name_len,
+            block_size);
+    }
+
+    // tells the caller if a file with parent and name exists.
+    fn lookup(&self, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        info!("lookup(parent_ino: {parent:#x?}, name: {name:?})");
+
+        let name = &*encode(name);
+
+        self.check_db_version();
+
+        // We call the readdir helper functions to ensure that we create the
+        // child inodes as soon as possible. This ensures that even if readdir
+        // is not called, we can operate with just lookup.
+        if parent == FUSE_ROOT_ID {
+            self.readdir_root(0, None);
+
+            if let Some(inode) = self.lookup_root_child(parent, name, reply) {
+                self.readdir_helper(inode, 0, None);
+            }
+        } else if parent == self.entries().get_or_create_query_directory() {
+
+            // stored queries are looked up by the name they are listed as or
+            // by their own name, before trying name as an ad-hoc query. Other
+            // names written as a stored query only exist once they are created
+            // with mkdir.
+            let query = match self.find_stored_query(name) {
+                Some(stored_query) => stored_query.query,
+                None if StoredQuery::is_stored_query(name) => {
+                    reply.error(libc::ENOENT);
+                    return;
+                }
+                None => name.to_string(),
+            };
+
+            let inode = self.entries().get_or_create_query_result_dir(
+                &query, name);
+            let query = self.entries().get_query(inode).unwrap_or_default()
+                .to_string();
+
+            info!("Running database query \"{query}\".");
+            if self.db().query(&query, false).is_err() {
+                self.entries().remove_child(parent, name);
+                reply.error(libc::ENOENT);
+
+            } else {
+                self.readdir_helper(inode, 0, None);
+                self.reply_entry(inode, reply);
+            }
+
+        } else {
+            // the children of the parent may have changed since they were
+            // listed, for example by a tagfs command run while mounted. Real
+            // directories are not tracked by the database, so they are always
+            // listed again.
+            let version = self.db_version.load(Ordering::Relaxed);
+            let listed = self.entries().is_listed_at(parent, version);
+            if !listed || self.is_real_dir(parent) {
+                self.readdir_helper(parent, 0, None);
+            }
+
+            let inode = self.entries().try_get_inode(parent, name);
+            if let Some(inode) = inode {
+                self.readdir_helper(inode, 0, None);
+                self.reply_entry(inode, reply);
+            } else {
+                reply.error(libc::ENOENT);
+            }
+        }
+    }
+
+    // the kernel no longer refers to the inode nlookup times.
+    fn forget(&self, inode: u64, nlookup: u64) {
+        info!("forget(inode: {inode:#x?}, nlookup: {nlookup})");
+
+        self.entries().forget(inode, nlookup);
+    }
+
+    fn batch_forget(&self, nodes: &[fuse_forget_one]) {
+        info!("batch_forget(nodes: {})", nodes.len());
+
+        let mut entries = self.entries();
+        for node in nodes {
+            entries.forget(node.nodeid, node.nlookup);
+        }
+    }
+
+    // returns the entries in a directory
+    fn readdir(&self, inode: u64, offset: i64, reply: ReplyDirectory) {
+        info!("readdir(inode: {inode:#x?}, offset: {offset:?})");
+
+        if inode == FUSE_ROOT_ID {
+            self.readdir_root(offset, Some(reply));
+        } else {
+            self.readdir_helper(inode, offset, Some(reply));
+        }
+    }
+
+    // returns the target for a given link inode.
+    fn readlink(&self, inode: u64, reply: ReplyData) {
+        info!("readlink(inode: {inode:#x?})");
+
+        // links to real files are only symlinks when the real file is
+        // missing.
+        if self.entries().get_attr(inode)
+            .is_some_and(|attr| attr.kind != FileType::Symlink)
+        {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        let path = self.entries().get_path(inode).map(str::to_string);
+        if let Some(path) = path.filter(|_| self.is_real(inode)) {
+            match std::fs::read_link(decode_path(&path)) {
+                Ok(target) => reply.data(target.as_os_str().as_bytes()),
+                Err(e) => reply.error(passthrough::errno(&e)),
+            }
+            return;
+        }
+
+        let tag_mapping_id = self.entries().get_link_target(inode);
+        if let Some(tag_mapping_id) = tag_mapping_id {
+            if let Ok(path) = self.db().get_path_from_id(tag_mapping_id) {
+                let dir_depth = self.entries().get_depth(inode) - 1;
+                let target = self.link_target(dir_depth, &path);
+                reply.data(target.as_os_str().as_bytes());
+                return;
+            }
+        }
+        error!("could not find link target for inode: {inode:#x?}.");
+        reply.error(libc::ENOENT);
+    }
+
+    // removes a tag from a path when its link is deleted from a tag or value
+    // directory.
+    fn unlink(&self, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        info!("unlink(parent_ino: {parent:#x?}, name: {name:?})");
+
+        let name = &*encode(name);
+
+        let Some(inode) = self.entries().try_get_inode(parent, name) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        let entry_type = self.entries().get_type(inode);
+
+        match entry_type {
+            Some(EntryType::Link) => {}
+            Some(EntryType::Passthrough | EntryType::ControlFile) => {
+                reply.error(libc::EPERM);
+                return;
+            }
+            Some(EntryType::AllTagsTerminal) => {
+                // deleting a tags file removes all tags from its path.
+                let Some(path) = self.entries().get_path(inode)
+                    .map(str::to_string)
+                else {
+                    reply.error(libc::ENOENT);
+                    return;
+                };
+
+                if let Err(e) = self.db_writer().untag_all(&path) {
+                    error!("could not untag \"{path}\": {e:?}");
+                    reply.error(libc::ENOENT);
+                    return;
+                }
+
+                self.entries().remove_child(parent, name);
+                reply.ok();
+                return;
+            }
+            Some(_) => {
+                reply.error(libc::EISDIR);
+                return;
+            }
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        }
+
+        match self.untag_link(parent, name, inode) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    // tags the target of a new link with the tag value pair of the directory
+    // it is created in.
+    fn symlink(&self, parent: u64, name: &OsStr, link: &Path,
+               reply: ReplyEntry)
+    {
+        info!("symlink(parent_ino: {parent:#x?}, name: {name:?}, \
+               link: {link:?})");
+
+        let Some((tag, value)) = self.dir_tag_value(parent) else {
+            reply.error(libc::EPERM);
+            return;
+        };
+
+        // we cannot know what a relative link is relative to, so only absolute
+        // links can be tagged.
+        if !link.is_absolute() {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let path = encode(link.as_os_str());
+        let path = self.config.unmap_root(path.trim_end_matches('/'));
+        let path = path.as_ref();
+
+        // the link is shown under the name its path is given among the other
+        // paths with the tag, so it cannot be created under any other name.
+        match self.new_link_name(parent, path, None) {
+            Some(display_name) if display_name != encode(name) => {
+                info!("link {name:?} would be shown as \"{display_name}\".");
+                reply.error(libc::EINVAL);
+                return;
+            }
+            Some(_) => {}
+            None => {
+                reply.error(libc::EEXIST);
+                return;
+            }
+        }
+
+        // a path that does not match the root query could not be shown.
+        let res = self.db_writer().tag_in_root(path, &tag, value.as_deref());
+        if res.is_sql_unique_cons_err() {
+            reply.error(libc::EEXIST);
+            return;
+        } else if let Err(e) = res {
+            error!("could not tag \"{path}\": {e:?}");
+            reply.error(libc::EINVAL);
+            return;
+        } else if matches!(res, Ok(false)) {
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        let paths = self.db().paths_with_tag(&tag, value.as_deref())
+            .unwrap_or_default();
+
+        if let Some(idx) = paths.iter().position(|(child, _)| child == path) {
+            let (inode, _) = self.get_or_create_link(parent, &paths, idx);
+            self.reply_entry(inode, reply);
+        } else {
+            error!("could not find \"{path}\" after tagging it.");
+            reply.error(libc::EIO);
+        }
+    }
+
+    // creates an empty tag in the root directory, an empty value in a tag
+    // directory or a stored query in the query directory.
+    fn mkdir(&self, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        info!("mkdir(parent_ino: {parent:#x?}, name: {name:?})");
+
+        let name = &*encode(name);
+
+        let parent_type = self.entries().get_type(parent);
+        let nested = self.entries().is_nested(parent);
+
+        let inode = if parent == FUSE_ROOT_ID {
+            if self.config.is_dir_name(name) {
+                reply.error(libc::EEXIST);
+                return;
+            }
+
+            if let Err(e) = self.db_writer().create_empty_tag(name) {
+                error!("could not create tag \"{name}\": {e:?}");
+                reply.error(libc::EEXIST);
+                return;
+            }
+
+            self.get_or_create_tag_directory(parent, name)
+
+        } else if matches!(parent_type, Some(EntryType::TagDir)) && !nested {
+            let tag = self.entries().get_name(parent)
+                .unwrap_or_default().to_string();
+
+            if let Err(e) = self.db_writer().create_empty_value(&tag, name) {
+                error!("could not create value \"{tag}={name}\": {e:?}");
+                reply.error(libc::EPERM);
+                return;
+            }
+
+            self.entries().get_or_create_value_directory(parent, name, name)
+
+        } else if parent == self.entries().get_or_create_query_directory() {
+            match self.create_stored_query(name) {
+                Ok(inode) => inode,
+                Err(errno) => {
+                    reply.error(errno);
+                    return;
+                }
+            }
+
+        } else {
+            reply.error(libc::EPERM);
+            return;
+        };
+
+        self.reply_entry(inode, reply);
+    }
+
+    // removes a tag or a value from every path, or deletes a stored query.
+    fn rmdir(&self, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        info!("rmdir(parent_ino: {parent:#x?}, name: {name:?})");
+
+        let name = &*encode(name);
+
+        let Some(inode) = self.entries().try_get_inode(parent, name) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        let nested = self.entries().is_nested(inode);
+        let entry_type = self.entries().get_type(inode);
+        let res = match entry_type {
+            Some(EntryType::TagDir) if !nested => {
+                self.db_writer().remove_tag(name)
+            }
+            Some(EntryType::ValueDir) if !nested => {
+                let tag = self.entries().get_parent_tag(inode)
+                    .unwrap_or_default().to_string();
+                let value = self.entries().get_tag_value(inode)
+                    .unwrap_or_default().to_string();
+                self.db_writer().remove_value(&tag, &value)
+            }
+            // ad-hoc queries are not stored, so they cannot be removed.
+            Some(EntryType::QueryResultDir) => {
+                let Some(stored_query) = self.find_stored_query(name) else {
+                    reply.error(libc::EPERM);
+                    return;
+                };
+                self.db_writer().delete_stored_query(&stored_query.name)
+                    .map(|_| ())
+            }
+            // removing a link to a directory removes the tag from its path,
+            // the same as unlink.
+            Some(EntryType::Link) if self.is_real_dir(inode) => {
+                match self.untag_link(parent, name, inode) {
+                    Ok(()) => reply.ok(),
+                    Err(errno) => reply.error(errno),
+                }
+                return;
+            }
+            Some(EntryType::Link | EntryType::AllTagsTerminal) => {
+                reply.error(libc::ENOTDIR);
+                return;
+            }
+            Some(_) => {
+                reply.error(libc::EPERM);
+                return;
+            }
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+
+        if let Err(e) = res {
+            error!("could not remove directory \"{name}\": {e:?}");
+            reply.error(libc::ENOENT);
+            return;
+        }
+
+        self.entries().remove_child(parent, name);
+        if let Some((tag, _)) = self.dir_tag_value(parent) {
+            self.prune_tag_dirs(&tag, None);
+        }
+
+        reply.ok();
+    }
+
+    // renames tags and values, or moves a link from one tag or value
+    // directory to another to retag its target.
+    fn rename(&self, parent: u64, name: &OsStr, newparent: u64,
+              newname: &OsStr, flags: u32, reply: ReplyEmpty)
+    {
+        info!("rename(parent_ino: {parent:#x?}, name: {name:?}, \
+               newparent_ino: {newparent:#x?}, newname: {newname:?})");
+
+        // swapping two entries has no meaningful equivalent in the database.
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        let (name, newname) = (&*encode(name), &*encode(newname));
+
+        let Some(inode) = self.entries().try_get_inode(parent, name) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        // make sure the destination directory is populated so that we can
+        // check if the new name is taken.
+        if newparent == FUSE_ROOT_ID {
+            self.readdir_root(0, None);
+        } else {
+            self.readdir_helper(newparent, 0, None);
+        }
+
+        // the control directory is not listed, so it has to be checked by
+        // name.
+        let is_dir_name = newparent == FUSE_ROOT_ID
+            && self.config.is_dir_name(newname);
+        if is_dir_name
+            || self.entries().try_get_inode(newparent, newname).is_some()
+        {
+            reply.error(libc::EEXIST);
+            return;
+        }
+
+        let nested = self.entries().is_nested(inode);
+        let entry_type = self.entries().get_type(inode);
+        let res = match entry_type {
+            Some(EntryType::TagDir)
+                if parent == FUSE_ROOT_ID && newparent == FUSE_ROOT_ID =>
+            {
+                self.db_writer().rename_tag(name, newname)
+            }
+            Some(EntryType::ValueDir) if !nested && newparent == parent => {
+                let tag = self.entries().get_parent_tag(inode)
+                    .unwrap_or_default().to_string();
+                let value = self.entries().get_tag_value(inode)
+                    .unwrap_or_default().to_string();
+                self.db_writer().rename_value(&tag, &value, newname)
+            }
+            Some(EntryType::Link) if newparent != parent => {
+                // the link is shown under the name of its path in the new
+                // directory, so it cannot be moved under any other name.
+                let moved = self.entries().get_link_target(inode);
+                let display_name = self.entry_path(inode).and_then(|path|
+                    self.new_link_name(newparent, &path, moved));
+                if display_name.is_some_and(|display_name|
+                    display_name != newname)
+                {
+                    reply.error(libc::EINVAL);
+                    return;
                 }
 
-                EntryType::QueryResultDir => {
-                    self.readdir_query(inode, offset, reply);
+                self.retag_link(inode, parent, newparent)
+            }
+            Some(_) => {
+                reply.error(libc::EPERM);
+                return;
+            }
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+
+        if let Err(e) = res {
+            error!("could not rename \"{name}\" to \"{newname}\": {e:?}");
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        self.entries().rename(parent, name, newparent, newname);
+        reply.ok();
+    }
+
+    // changes the size of a tags file or the query file, any other
+    // attributes cannot be changed.
+    fn setattr(&self, inode: u64, size: Option<u64>, fh: Option<u64>,
+               reply: ReplyAttr)
+    {
+        info!("setattr(inode: {inode:#x?}, size: {size:?}, fh: {fh:?})");
+
+        if let Some(size) = size {
+            let entry_type = self.entries().get_type(inode);
+            let control_file = self.entries().get_control_file(inode);
+            let is_query_file = control_file == Some(ControlFile::Query);
+            if !is_query_file
+                && !matches!(entry_type, Some(EntryType::AllTagsTerminal))
+            {
+                reply.error(libc::EPERM);
+                return;
+            }
+
+            // the kernel does not always give us the file handle when a file
+            // is opened with O_TRUNC.
+            let mut open_files = self.open_files();
+            let file = match fh {
+                Some(fh) => open_files.get_mut(fh),
+                None => open_files.find_mut(inode),
+            };
+
+            // truncating an open file only changes the buffered contents,
+            // otherwise the truncated contents are saved straight away. The
+            // contents are text, so they are never padded to a larger size.
+            // The query file has no query unless it is open.
+            if let Some(file) = file {
+                file.truncate(size as usize);
+            } else if !is_query_file {
+                drop(open_files);
+
+                let path = match self.tags_file_path(inode) {
+                    Ok(path) => path,
+                    Err(errno) => {
+                        reply.error(errno);
+                        return;
+                    }
+                };
+                let mut contents = self.tags_file_contents(&path).into_bytes();
+                contents.truncate(size as usize);
+
+                if let Err(errno) = self.save_tags_file(&path, &contents) {
+                    reply.error(errno);
+                    return;
                 }
+            }
+        }
+
+        self.refresh_tags_file_size(inode);
+        let attr = self.entries().get_attr(inode).copied();
+        if let Some(attr) = attr {
+            reply.attr(&self.config.ttl, &attr);
+        } else {
+            reply.error(libc::ENOENT);
+        }
+    }
+
+    // lists the tags of a path as extended attributes. The kernel only lets
+    // user attributes be read and written on regular files and directories,
+    // so the tags of a link can be listed but only read and written in
+    // passthrough mode, where links are shown as real files.
+    fn listxattr(&self, inode: u64, size: u32, reply: ReplyXattr) {
+        info!("listxattr(inode: {inode:#x?}, size: {size:?})");
+
+        let mut names = Vec::new();
+        if let Some(path) = self.entry_path(inode) {
+            let xattrs = self.db().xattrs(&path).unwrap_or_default();
+            for name in xattrs.into_keys() {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+        }
+
+        reply_xattr(size, &names, reply);
+    }
+
+    // returns the values of a tag on a path.
+    fn getxattr(&self, inode: u64, name: &OsStr, size: u32,
+                reply: ReplyXattr)
+    {
+        info!("getxattr(inode: {inode:#x?}, name: {name:?}, size: {size:?})");
 
-                EntryType::AllTagsDir | EntryType::AllTagsIntermediate => {
-                    self.readdir_all_tags(inode, offset, reply);
-                }
+        let payload = self.entry_path(inode)
+            .zip(name.to_str())
+            .and_then(|(path, name)| self.db().xattrs(&path).ok()
+                .and_then(|mut xattrs| xattrs.remove(name)));
 
-                // cannot readdir something that is not a directory and the
-                // root is already covered.
-                EntryType::Root | EntryType::Link | EntryType::AllTagsTerminal
-                    => unreachable!(),
-            }
+        if let Some(payload) = payload {
+            reply_xattr(size, &payload, reply);
+        } else {
+            reply.error(libc::ENODATA);
         }
     }
-}
 
-impl fuser::Filesystem for TagFS {
+    // tags a path, the payload contains the values of the tag.
+    fn setxattr(&self, inode: u64, name: &OsStr, value: &[u8], flags: i32,
+                reply: ReplyEmpty)
+    {
+        info!("setxattr(inode: {inode:#x?}, name: {name:?})");
 
-    // look up inode and get its attrs.
-    fn getattr(&mut self, _req: &Request<'_>, inode: u64, reply: ReplyAttr) {
-        info!("getattr(inode: {inode:#x?})");
+        let Some(path) = self.entry_path(inode) else {
+            reply.error(libc::EPERM);
+            return;
+        };
+
+        let Some(tag) = name.to_str()
+            .and_then(|name| name.strip_prefix(XATTR_PREFIX))
+            .filter(|tag| !tag.is_empty())
+        else {
+            reply.error(libc::ENOTSUP);
+            return;
+        };
+
+        let Ok(payload) = std::str::from_utf8(value) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+
+        let exists = self.db().tags(&path)
+            .is_ok_and(|tags| tags.iter().any(|t| t.tag.name == tag));
+
+        if exists && flags & libc::XATTR_CREATE != 0 {
+            reply.error(libc::EEXIST);
+            return;
+        } else if !exists && flags & libc::XATTR_REPLACE != 0 {
+            reply.error(libc::ENODATA);
+            return;
+        }
+
+        let values = parse_xattr_payload(payload);
+
+        if let Err(e) = self.db_writer().set_tag(&path, tag, &values) {
+            error!("could not tag \"{path}\" with \"{tag}\": {e:?}");
+            reply.error(libc::EINVAL);
+            return;
+        }
 
-        reply.attr(&TTL, self.entries.get_attr(inode));
+        reply.ok();
     }
 
-    // tells the caller if a file with parent and name exists.
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr,
-              reply: ReplyEntry)
-    {
-        info!("lookup(parent_ino: {parent:#x?}, name: {name:?})");
+    // removes a tag from a path.
+    fn removexattr(&self, inode: u64, name: &OsStr, reply: ReplyEmpty) {
+        info!("removexattr(inode: {inode:#x?}, name: {name:?})");
 
-        // for now at least, we are only going to allow valid unicode.
-        let name = name.to_str().unwrap_or_else(|| {
-                error!("tried to lookup non-unicode name \"{name:?}\"");
-                panic!("tried to lookup non-unicode name \"{name:?}\"");
-            });
+        let Some(path) = self.entry_path(inode) else {
+            reply.error(libc::ENODATA);
+            return;
+        };
 
-        // We call the readdir helper functions to ensure that we create the
-        // child inodes as soon as possible. This ensures that even if readdir
-        // is not called, we can operate with just lookup.
-        if parent == FUSE_ROOT_ID {
-            self.readdir_root(0, None);
+        let Some(tag) = name.to_str()
+            .and_then(|name| name.strip_prefix(XATTR_PREFIX))
+        else {
+            reply.error(libc::ENODATA);
+            return;
+        };
 
-            if let Some(inode) = self.lookup_root_child(parent, name, reply) {
-                self.readdir_helper(inode, 0, None);
+        let mut db = self.db_writer();
+        match db.tags(&path) {
+            Ok(tags) if tags.iter().any(|t| t.tag.name == tag) => {}
+            Ok(_) => {
+                reply.error(libc::ENODATA);
+                return;
+            }
+            Err(e) => {
+                error!("could not read the tags of \"{path}\": {e:?}");
+                reply.error(libc::EIO);
+                return;
             }
-        } else if parent == self.entries.get_or_create_query_directory() {
+        }
+
+        // without a value every mapping of the tag is removed, whatever its
+        // value.
+        if let Err(e) = db.untag(&path, tag, None) {
+            error!("could not untag \"{path}\": {e:?}");
+            reply.error(libc::EIO);
+            return;
+        }
 
-            let inode = self.entries.get_or_create_query_result_dir(
-                name, name);
-            let query = self.entries.get_query(inode);
+        reply.ok();
+    }
 
-            info!("Running database query \"{query}\".");
-            if self.db.query(query, false).is_err() {
-                reply.error(libc::ENOENT);
+    fn open(&self, inode: u64, flags: i32, reply: ReplyOpen) {
+        info!("open(inode: {inode:#x?}, flags: {flags:#x?})");
+
+        if self.is_real(inode) {
+            self.open_real(inode, flags, reply);
+            return;
+        }
+
+        let control_file = self.entries().get_control_file(inode);
+        if let Some(file) = control_file {
+            self.open_control_file(inode, file, flags, reply);
+            return;
+        }
+
+        let path = match self.tags_file_path(inode) {
+            Ok(path) => path,
+            Err(errno) => {
+                error!("tried to open a file that is not a file! inode: \
+                        {inode:#x?}.");
+                reply.error(errno);
+                return;
+            }
+        };
 
+        // files opened for writing are buffered until they are flushed. The
+        // page cache is bypassed because the contents are generated on the
+        // fly and may change at any time.
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            let contents = if flags & libc::O_TRUNC != 0 {
+                Vec::new()
             } else {
-                let attr = *self.entries.get_attr(inode);
+                self.tags_file_contents(&path).into_bytes()
+            };
 
-                self.readdir_helper(inode, 0, None);
-                reply.entry(&TTL, &attr, 0);
+            let fh = self.open_files().open(inode, contents);
+            reply.opened(fh, FOPEN_DIRECT_IO);
+        } else {
+            reply.opened(0, FOPEN_DIRECT_IO);
+        }
+    }
+
+    fn read(&self, inode: u64, fh: u64, offset: i64, size: u32,
+            reply: ReplyData)
+    {
+        info!("read(inode: {inode:#x?}, offset: {offset:?}, size: {size:?})");
+
+        let real_file = self.open_files().get_real(fh);
+        if let Some(file) = real_file {
+            match passthrough::read(&file, offset as u64, size as usize) {
+                Ok(buf) => reply.data(&buf),
+                Err(e) => {
+                    error!("could not read inode {inode:#x?}: {e:?}");
+                    reply.error(passthrough::errno(&e));
+                }
+            }
+            return;
+        }
+
+        // the query file reads the results of the query written to the same
+        // handle, files open for writing are read from their buffer and the
+        // others are generated on the fly.
+        let control_file = self.entries().get_control_file(inode);
+        let contents = self.open_files().get_mut(fh)
+            .map(|file| file.contents.clone());
+
+        let buf = if control_file == Some(ControlFile::Query) {
+            match self.query_file_results(fh) {
+                Ok(buf) => buf,
+                Err(errno) => {
+                    reply.error(errno);
+                    return;
+                }
+            }
+        } else if let Some(contents) = contents {
+            contents
+        } else if let Some(file) = control_file {
+            self.control_file_contents(file)
+        } else {
+            match self.tags_file_path(inode) {
+                Ok(path) => self.tags_file_contents(&path).into_bytes(),
+                Err(errno) => {
+                    error!("tried to read a file that is not a file! inode: \
+                            {inode:#x?}.");
+                    reply.error(errno);
+                    return;
+                }
             }
+        };
 
-        } else if let Some(inode) = self.entries.try_get_inode(parent, name) {
-            let attr = *self.entries.get_attr(inode);
+        let start = (offset as usize).min(buf.len());
+        let end = start.saturating_add(size as usize).min(buf.len());
+        reply.data(&buf[start..end]);
+    }
 
-            self.readdir_helper(inode, 0, None);
+    fn write(&self, inode: u64, fh: u64, offset: i64, data: &[u8],
+             reply: ReplyWrite)
+    {
+        info!("write(inode: {inode:#x?}, offset: {offset:?}, \
+               size: {:?})", data.len());
 
-            reply.entry(&TTL, &attr, 0);
+        if let Some(file) = self.open_files().get_mut(fh) {
+            file.write(offset as usize, data);
+            reply.written(data.len() as u32);
         } else {
-            reply.error(libc::ENOENT);
+            reply.error(libc::EBADF);
         }
     }
 
-    // returns the entries in a directory
+    // called on every close of a file, we save any changes to a tags file
+    // here because this is the last chance to report an error to the user.
+    fn flush(&self, inode: u64, fh: u64, reply: ReplyEmpty) {
+        info!("flush(inode: {inode:#x?}, fh: {fh:?})");
+
+        match self.flush_open_file(fh) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn release(&self, inode: u64, fh: u64, reply: ReplyEmpty) {
+        info!("release(inode: {inode:#x?}, fh: {fh:?})");
+
+        let res = self.flush_open_file(fh);
+        self.open_files().close(fh);
+
+        match res {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+}
+
+/// Implements the [`fuser::Filesystem`] trait by handing each request to a
+/// worker thread, which serves it from the shared [`TagFS`]. Forgets are
+/// served straight away, because they are cheap and must not overtake the
+/// lookups they balance.
+#[derive(Debug)]
+struct Dispatcher {
+    tagfs: Arc<TagFS>,
+    workers: Workers,
+}
+
+impl Dispatcher {
+    fn new(tagfs: TagFS) -> std::io::Result<Self> {
+        let workers = Workers::new(tagfs.config.threads)?;
+        Ok(Self { tagfs: Arc::new(tagfs), workers })
+    }
+
+    /// Queue a request to be served on a worker thread.
+    fn run(&self, request: impl FnOnce(&TagFS) + Send + 'static) {
+        let tagfs = Arc::clone(&self.tagfs);
+        self.workers.execute(move || request(&tagfs));
+    }
+}
+
+impl fuser::Filesystem for Dispatcher {
+
+    fn destroy(&mut self) {
+        self.tagfs.destroy();
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, inode: u64, reply: ReplyAttr) {
+        self.run(move |tagfs| tagfs.getattr(inode, reply));
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _inode: u64, reply: ReplyStatfs) {
+        self.run(move |tagfs| tagfs.statfs(reply));
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr,
+              reply: ReplyEntry)
+    {
+        let name = name.to_owned();
+        self.run(move |tagfs| tagfs.lookup(parent, &name, reply));
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, inode: u64, nlookup: u64) {
+        self.tagfs.forget(inode, nlookup);
+    }
+
+    fn batch_forget(&mut self, _req: &Request<'_>, nodes: &[fuse_forget_one]) {
+        self.tagfs.batch_forget(nodes);
+    }
+
     fn readdir(&mut self, _req: &Request, inode: u64, _fh: u64, offset: i64,
                reply: ReplyDirectory)
     {
-        info!("readdir(inode: {inode:#x?}, offset: {offset:?})");
-
-        if inode == FUSE_ROOT_ID {
-            self.readdir_root(offset, Some(reply));
-        } else {
-            self.readdir_helper(inode, offset, Some(reply));
-        }
+        self.run(move |tagfs| tagfs.readdir(inode, offset, reply));
     }
 
-    // returns the target for a given link inode.
     fn readlink(&mut self, _req: &Request, inode: u64, reply: ReplyData) {
-        info!("readlink(inode: {inode:#x?})");
-        if let Some(tag_mapping_id) = self.entries.get_link_target(inode) {
-            if let Ok(target) = self.db.get_path_from_id(tag_mapping_id) {
-                reply.data(target.as_bytes());
-                return;
-            }
-        }
-        error!("could not find link target for inode: {inode:#x?}.");
-        panic!("could not find link target for inode: {inode:#x?}.");
+        self.run(move |tagfs| tagfs.readlink(inode, reply));
     }
 
-    fn open(&mut self, _req: &Request, inode: u64, _flags: i32,
-            reply: ReplyOpen)
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr,
+              reply: ReplyEmpty)
     {
-        info!("open(inode: {inode:#x?})");
+        let name = name.to_owned();
+        self.run(move |tagfs| tagfs.unlink(parent, &name, reply));
+    }
 
-        if !matches!(self.entries.get_type(inode), EntryType::AllTagsTerminal)
-        {
-            error!("tried to open a file that is not a file! inode: \
-                    {inode:#x?}.");
-            panic!("tried to open a file that is not a file! inode: \
-                    {inode:#x?}.");
-        }
+    fn symlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr,
+               link: &Path, reply: ReplyEntry)
+    {
+        let name = name.to_owned();
+        let link = link.to_owned();
+        self.run(move |tagfs| tagfs.symlink(parent, &name, &link, reply));
+    }
 
-        reply.opened(0, 0);
+    fn mkdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr,
+             _mode: u32, _umask: u32, reply: ReplyEntry)
+    {
+        let name = name.to_owned();
+        self.run(move |tagfs| tagfs.mkdir(parent, &name, reply));
     }
 
-    fn read(&mut self, _req: &Request, inode: u64, _fh: u64, offset: i64,
-            size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData)
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr,
+             reply: ReplyEmpty)
     {
-        info!("read(inode: {inode:#x?}, offset: {offset:?}, size: {size:?})");
+        let name = name.to_owned();
+        self.run(move |tagfs| tagfs.rmdir(parent, &name, reply));
+    }
 
-        if !matches!(self.entries.get_type(inode), EntryType::AllTagsTerminal)
-        {
-            error!("tried to read a file that is not a file! inode: \
-                    {inode:#x?}.");
-            panic!("tried to read a file that is not a file! inode: \
-                    {inode:#x?}.");
-        }
+    fn rename(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr,
+              newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty)
+    {
+        let name = name.to_owned();
+        let newname = newname.to_owned();
+        self.run(move |tagfs|
+            tagfs.rename(parent, &name, newparent, &newname, flags, reply));
+    }
 
-        let path = self.entries.get_path(inode);
+    fn setattr(&mut self, _req: &Request<'_>, inode: u64, _mode: Option<u32>,
+               _uid: Option<u32>, _gid: Option<u32>, size: Option<u64>,
+               _atime: Option<TimeOrNow>, _mtime: Option<TimeOrNow>,
+               _ctime: Option<SystemTime>, fh: Option<u64>,
+               _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>,
+               _bkuptime: Option<SystemTime>, _flags: Option<u32>,
+               reply: ReplyAttr)
+    {
+        self.run(move |tagfs| tagfs.setattr(inode, size, fh, reply));
+    }
 
-        let mut buf = String::with_capacity(1024);
+    fn listxattr(&mut self, _req: &Request<'_>, inode: u64, size: u32,
+                 reply: ReplyXattr)
+    {
+        self.run(move |tagfs| tagfs.listxattr(inode, size, reply));
+    }
 
-        if let Ok(tags) = self.db.tags(path) {
-            for tag in tags {
-                // unwrap is okay here, because we are writing to an in-memory
-                // string buffer.
-                writeln!(buf, "{}", crate::db::SimpleTagFormatter::from(&tag))
-                    .unwrap();
-            }
-        }
+    fn getxattr(&mut self, _req: &Request<'_>, inode: u64, name: &OsStr,
+                size: u32, reply: ReplyXattr)
+    {
+        let name = name.to_owned();
+        self.run(move |tagfs| tagfs.getxattr(inode, &name, size, reply));
+    }
 
-        if (offset as usize) < buf.len() {
-            reply.data(&buf.as_bytes()[offset as usize..]);
-        }
+    fn setxattr(&mut self, _req: &Request<'_>, inode: u64, name: &OsStr,
+                value: &[u8], flags: i32, _position: u32, reply: ReplyEmpty)
+    {
+        let name = name.to_owned();
+        let value = value.to_vec();
+        self.run(move |tagfs|
+            tagfs.setxattr(inode, &name, &value, flags, reply));
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, inode: u64, name: &OsStr,
+                   reply: ReplyEmpty)
+    {
+        let name = name.to_owned();
+        self.run(move |tagfs| tagfs.removexattr(inode, &name, reply));
+    }
+
+    fn open(&mut self, _req: &Request, inode: u64, flags: i32,
+            reply: ReplyOpen)
+    {
+        self.run(move |tagfs| tagfs.open(inode, flags, reply));
+    }
+
+    fn read(&mut self, _req: &Request, inode: u64, fh: u64, offset: i64,
+            size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData)
+    {
+        self.run(move |tagfs| tagfs.read(inode, fh, offset, size, reply));
+    }
+
+    fn write(&mut self, _req: &Request<'_>, inode: u64, fh: u64, offset: i64,
+             data: &[u8], _write_flags: u32, _flags: i32,
+             _lock_owner: Option<u64>, reply: ReplyWrite)
+    {
+        let data = data.to_vec();
+        self.run(move |tagfs| tagfs.write(inode, fh, offset, &data, reply));
+    }
+
+    fn flush(&mut self, _req: &Request<'_>, inode: u64, fh: u64,
+             _lock_owner: u64, reply: ReplyEmpty)
+    {
+        self.run(move |tagfs| tagfs.flush(inode, fh, reply));
+    }
+
+    fn release(&mut self, _req: &Request<'_>, inode: u64, fh: u64,
+               _flags: i32, _lock_owner: Option<u64>, _flush: bool,
+               reply: ReplyEmpty)
+    {
+        self.run(move |tagfs| tagfs.release(inode, fh, reply));
     }
 }
 
-/// Call this function with a path to mount the filesystem.
+/// Call this function with a path to mount the filesystem with the options in
+/// config.
 ///
 /// Blocks until the filesystem is unmounted.
 ///
 /// # Errors
-/// Returns an error if one is thrown by FUSE.
-pub fn mount(mnt_point: &str, db: Database) -> std::io::Result<()> {
+/// Returns an error if config is invalid or if one is thrown by FUSE.
+pub fn mount(mnt_point: &str, mut db: Database, config: MountConfig)
+    -> std::io::Result<()>
+{
     info!("Mounting filesystem at \"{mnt_point}\"");
 
+    config.validate().map_err(|e|
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    if let Some(root_query) = &config.root_query {
+        db.set_root_query(root_query).map_err(|e|
+            std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("could not mount \"{root_query}\": {e:#}")))?;
+    }
+
+    for tag in db.all_tags().unwrap_or_default() {
+        if config.is_dir_name(&tag) {
+            warn!("tag \"{tag}\" is hidden by the directory with the same \
+                   name, mount with a different directory name to show it.");
+        }
+    }
+
     // force initialisation of the lazy cell to remember the mount time.
     Lazy::force(&MOUNT_TIME);
 
-    let mnt_options = {
-        use fuser::MountOption::*;
-        &[AutoUnmount, AllowOther, RO]
-    };
+    // relative links are resolved from the real location of the mount point.
+    let mount_point = std::fs::canonicalize(mnt_point)?;
+
+    let mnt_options = config.mount_options();
+    let poll_interval = config.poll_interval;
+    let dispatcher = Dispatcher::new(TagFS::new(db, config, mount_point))?;
+    let tagfs = Arc::downgrade(&dispatcher.tagfs);
+
+    let mut session = fuser::Session::new(dispatcher, mnt_point.as_ref(),
+                                          &mnt_options)?;
+    let notifier = session.notifier();
+    std::thread::Builder::new()
+        .name(String::from("poll"))
+        .spawn(move || poll_db(&tagfs, &notifier, poll_interval))?;
+
+    session.run()
+}
+
+/// Checks the database for changes every interval and tells the kernel to
+/// forget its cached entries when it has changed. Stops once the filesystem
+/// is unmounted.
+fn poll_db(tagfs: &Weak<TagFS>, notifier: &Notifier, interval: Duration) {
+    let mut last_version = None;
+
+    loop {
+        let Some(tagfs) = tagfs.upgrade() else {
+            return;
+        };
+
+        match tagfs.db.version() {
+            Ok(version) if last_version != Some(version) => {
+                if last_version.is_some() {
+                    info!("database changed, invalidating entries.");
+                    tagfs.invalidate_entries(notifier);
+                }
+                last_version = Some(version);
+            }
+            Ok(_) => {}
+            Err(e) => error!("could not check the database version: {e:?}"),
+        }
+
+        drop(tagfs);
+        std::thread::sleep(interval);
+    }
+}
+
+/// Logs the error of a notification, unless the kernel did not have the
+/// entry cached.
+fn ignore_uncached(result: std::io::Result<()>) {
+    if let Err(e) = result {
+        if e.raw_os_error() != Some(libc::ENOENT) {
+            warn!("could not invalidate an entry: {e}");
+        }
+    }
+}
 
-    let tagfs = TagFS::new(db);
+/// Locks mutex, even if a thread panicked while holding it. The state it
+/// protects is still consistent, because it is only locked for single calls.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
-    fuser::mount2(tagfs, mnt_point, mnt_options)
+/// Reply to a getxattr or listxattr request. A size of zero asks for the size
+/// of the data rather than the data itself.
+fn reply_xattr(size: u32, data: &[u8], reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() <= size as usize {
+        reply.data(data);
+    } else {
+        reply.error(libc::ERANGE);
+    }
 }
 
 /// Converts a full path (such as "my/long/path") to its final component.
-fn sanitise_path<T: AsRef<str>>(path: &str, path_idx: usize,
-                                siblings: impl Iterator<Item=T>)
+/// Siblings with the same final component are told apart as duplicate_names
+/// says. The siblings are given with their TagMappingIDs, which number the
+/// paths in counter and parent mode so that their names do not change when
+/// other paths are tagged or untagged.
+fn sanitise_path<T: AsRef<str>>(path: &str, tag_mapping_id: u64,
+                                siblings: impl Iterator<Item=(T, u64)>,
+                                duplicate_names: DuplicateNames)
     -> String
 {
 
+    // paths without a final component (such as "/") cannot be used as a
+    // file name, so they are shown the same way sanitise_value would.
     fn basename(path: &str) -> &str {
-        let path = camino::Utf8Path::new(path);
-        let Some(file_name) = path.file_name() else {
-            panic!("invalid path \"{}\" without final component.",
-                   path);
-        };
+        camino::Utf8Path::new(path).file_name().unwrap_or("_")
+    }
 
-        file_name
+    fn parent_name(path: &str) -> &str {
+        camino::Utf8Path::new(path).parent().map_or("_", |parent|
+            basename(parent.as_str()))
     }
 
     let path_basename = basename(path);
 
-    let mut any_path_same_name = false;
-    let n = siblings.enumerate()
-        .fold(0, |acc, (idx, name)| {
-            if basename(name.as_ref()) == path_basename {
-                if idx != path_idx {
-                    any_path_same_name = true;
-                }
-                if idx < path_idx {
-                    return acc + 1;
-                }
+    // the other paths with the same final component.
+    let same_name: Vec<_> = siblings
+        .filter(|(name, id)| *id != tag_mapping_id
+            && basename(name.as_ref()) == path_basename)
+        .map(|(name, id)| (name.as_ref().to_string(), id))
+        .collect();
+
+    let counter = format!(" ({tag_mapping_id})");
+    match duplicate_names {
+        // the hash only depends on the path, so it is added even when no
+        // other path has the same name.
+        DuplicateNames::Hash => {
+            let hash = inode_generator::hash(path.bytes()) as u32;
+            insert_before_extension(path_basename, &format!(" [{hash:08x}]"))
+        }
+        _ if same_name.is_empty() => String::from(path_basename),
+        DuplicateNames::Suffix => {
+            let position = same_name.iter()
+                .filter(|(_, id)| *id < tag_mapping_id)
+                .count();
+            format!("{path_basename}.{position}")
+        }
+        DuplicateNames::Counter => {
+            insert_before_extension(path_basename, &counter)
+        }
+        DuplicateNames::Parent => {
+            let parent = parent_name(path);
+            if same_name.iter().any(|(name, _)| parent_name(name) == parent) {
+                format!("{parent} - {}",
+                    insert_before_extension(path_basename, &counter))
+            } else {
+                format!("{parent} - {path_basename}")
             }
-            return acc;
-        });
+        }
+    }
+}
 
-    if any_path_same_name {
-        format!("{path_basename}.{n}")
-    } else {
-        String::from(path_basename)
+/// Inserts s before the extension of name, or at the end if it has none.
+fn insert_before_extension(name: &str, s: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{stem}{s}.{extension}")
+        }
+        _ => format!("{name}{s}"),
     }
 }
 
@@ -571,22 +2451,48 @@ fn sanitise_value(path: &str) -> String {
     path.replace('/', "_")
 }
 
+/// Returns target relative to a directory that is depth components below the
+/// mount point, which must be absolute.
+fn relative_target(mount_point: &Path, depth: usize, target: &Path)
+    -> PathBuf
+{
+    let mount_point = mount_point.components().collect::<Vec<_>>();
+    let target = target.components().collect::<Vec<_>>();
+
+    let common = mount_point.iter().zip(&target)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in 0..mount_point.len() - common + depth {
+        relative.push("..");
+    }
+    relative.extend(&target[common..]);
+
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    relative
+}
+
 mod tests {
     #[test]
     fn sanitise_path_test() {
+        use super::DuplicateNames::Suffix;
+
         let path1 = "/my/long/file/path.txt";
         let path2 = "/my/other/long/file/path.txt";
         let path3 = "/some/other/file/path.txt";
         let siblings = vec![path1, path2, "/some/other/file", path3];
 
         assert_eq!(
-            super::sanitise_path(path1, 0, siblings.iter()),
+            super::sanitise_path(path1, 1, siblings.iter().zip(1..), Suffix),
             String::from("path.txt.0")
         );
 
         let siblings = vec![path1, "/some/other/file", path2, path3];
         assert_eq!(
-            super::sanitise_path(path2, 2, siblings.iter()),
+            super::sanitise_path(path2, 3, siblings.iter().zip(1..), Suffix),
             String::from("path.txt.1")
         );
 
@@ -595,8 +2501,95 @@ mod tests {
             path, "/some/unrelated/other/path.rs", "/another/random/path.jpg"
         ];
         assert_eq!(
-            super::sanitise_path(path, 0, siblings.iter()),
+            super::sanitise_path(path, 1, siblings.iter().zip(1..), Suffix),
             String::from("path.txt")
         );
+        assert_eq!(
+            super::sanitise_path("/", 1, ["/"].iter().zip(1..), Suffix),
+            String::from("_")
+        );
+    }
+
+    #[test]
+    fn duplicate_names_test() {
+        use super::DuplicateNames::{Counter, Hash, Parent};
+
+        let siblings = [
+            "/films/Heat (1995)/cover.jpg",
+            "/films/Alien (1979)/cover.jpg",
+            "/music/Heat/cover.jpg",
+            "/films/Alien (1979)/extras/.cover",
+            "/music/Alien/extras/.cover",
+            "/films/Heat (1995)/poster.jpg",
+        ];
+        let name = |id: u64, duplicate_names| super::sanitise_path(
+            siblings[id as usize - 1], id, siblings.iter().zip(1..),
+            duplicate_names);
+
+        assert_eq!(name(1, Counter), "cover (1).jpg");
+        assert_eq!(name(3, Counter), "cover (3).jpg");
+        assert_eq!(name(5, Counter), ".cover (5)");
+        assert_eq!(name(6, Counter), "poster.jpg");
+
+        assert_eq!(name(1, Parent), "Heat (1995) - cover.jpg");
+        assert_eq!(name(2, Parent), "Alien (1979) - cover.jpg");
+        assert_eq!(name(4, Parent), "extras - .cover (4)");
+        assert_eq!(name(5, Parent), "extras - .cover (5)");
+        assert_eq!(name(6, Parent), "poster.jpg");
+
+        // the hash only depends on the path, and is added to unique names
+        // as well.
+        let hash = name(1, Hash);
+        assert!(hash.starts_with("cover [") && hash.ends_with("].jpg"));
+        assert_eq!(hash, super::sanitise_path(siblings[0], 1,
+            std::iter::empty::<(&str, u64)>(), Hash));
+        assert_ne!(hash, name(2, Hash));
+        assert!(name(6, Hash).starts_with("poster ["));
+    }
+
+    #[test]
+    fn duplicate_names_untag_test() {
+        use super::DuplicateNames::{Counter, Parent};
+
+        let siblings = [
+            ("/films/Heat (1995)/extras/cover.jpg", 4),
+            ("/films/Alien (1979)/extras/cover.jpg", 7),
+            ("/music/Alien/extras/cover.jpg", 9),
+        ];
+        let names = |siblings: &[(&str, u64)], duplicate_names| siblings
+            .iter()
+            .map(|(path, id)| super::sanitise_path(path, *id,
+                siblings.iter().map(|(path, id)| (path, *id)),
+                duplicate_names))
+            .collect::<Vec<_>>();
+
+        // untagging the first path does not rename the others.
+        for duplicate_names in [Counter, Parent] {
+            let before = names(&siblings, duplicate_names);
+            let after = names(&siblings[1..], duplicate_names);
+            assert_eq!(before[1..], after);
+        }
+        assert_eq!(names(&siblings[1..], Counter),
+            ["cover (7).jpg", "cover (9).jpg"]);
+    }
+
+    #[test]
+    fn relative_target_test() {
+        use std::path::Path;
+
+        let mount_point = Path::new("/media/hdd/tags");
+        let target = Path::new("/media/hdd/films/Heat (1995)");
+        assert_eq!(
+            super::relative_target(mount_point, 2, target),
+            Path::new("../../../films/Heat (1995)")
+        );
+        assert_eq!(
+            super::relative_target(mount_point, 0, Path::new("/data")),
+            Path::new("../../../data")
+        );
+        assert_eq!(
+            super::relative_target(mount_point, 0, mount_point),
+            Path::new(".")
+        );
     }
 }
@@ -0,0 +1,266 @@
+//! options that control how the filesystem is mounted and presented.
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+use fuser::MountOption;
+
+use crate::fs::control::CONTROL_DIR_NAME;
+use crate::fs::mounts::FS_NAME;
+
+/// Options for [`crate::fs::mount`]. The default matches a plain
+/// `tagfs mount`.
+#[derive(Clone, Debug)]
+pub struct MountConfig {
+    /// Maximum number of tag directories that can be nested in a path, one
+    /// disables nesting.
+    pub max_depth: usize,
+    /// Show links as the real files and directories they refer to, rather
+    /// than as symlinks.
+    pub passthrough: bool,
+    /// Links report the times and owner of the path they refer to.
+    pub target_attrs: bool,
+    /// How long the kernel may cache entries and their attributes.
+    pub ttl: Duration,
+    /// How often the database is checked for changes made by other
+    /// processes, which the kernel is told to forget.
+    pub poll_interval: Duration,
+    /// Prefixes of the paths in the database and the prefixes that replace
+    /// them in the targets of links, for when the tagged paths are mounted
+    /// somewhere else, for example in a container. The first matching prefix
+    /// is replaced.
+    pub map_roots: Vec<(String, String)>,
+    /// Make the targets of links relative to the link, so that the mount
+    /// keeps working wherever the mount point and the tagged paths are moved
+    /// together.
+    pub relative_links: bool,
+    /// Only show the paths that match this query, everywhere in the mount.
+    pub root_query: Option<String>,
+    /// How links to paths with the same file name are told apart.
+    pub duplicate_names: DuplicateNames,
+    /// Name of the directory in the root that contains the queries.
+    pub query_dir_name: String,
+    /// Name of the directory in the root that mirrors every tagged path.
+    pub all_tags_dir_name: String,
+    /// Permission bits of the directories.
+    pub dir_perm: u16,
+    /// Permission bits of the tags files.
+    pub file_perm: u16,
+    /// Owner of every entry.
+    pub uid: u32,
+    /// Group of every entry.
+    pub gid: u32,
+    /// Unmount when the process exits. FUSE only allows this together with
+    /// allow_other, which is added when it is not set.
+    pub auto_unmount: bool,
+    /// Allow other users to access the mount, this requires
+    /// user_allow_other in /etc/fuse.conf.
+    pub allow_other: bool,
+    /// Mount read only.
+    pub read_only: bool,
+    /// Number of threads that serve requests, so that a slow query does not
+    /// hold up requests for other directories.
+    pub threads: usize,
+}
+
+impl Default for MountConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: 3,
+            passthrough: false,
+            target_attrs: false,
+            ttl: Duration::from_secs(1),
+            poll_interval: Duration::from_secs(1),
+            map_roots: Vec::new(),
+            relative_links: false,
+            root_query: None,
+            duplicate_names: DuplicateNames::default(),
+            query_dir_name: String::from("?"),
+            all_tags_dir_name: String::from("tags"),
+            dir_perm: 0o755,
+            file_perm: 0o644,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            auto_unmount: true,
+            allow_other: false,
+            read_only: false,
+            threads: 4,
+        }
+    }
+}
+
+impl MountConfig {
+    /// Returns the FUSE options to mount with.
+    pub(super) fn mount_options(&self) -> Vec<MountOption> {
+        let mut options = vec![
+            MountOption::FSName(String::from(FS_NAME)),
+            MountOption::Subtype(String::from(FS_NAME)),
+            if self.read_only { MountOption::RO } else { MountOption::RW },
+        ];
+
+        if self.auto_unmount {
+            options.push(MountOption::AutoUnmount);
+        }
+        if self.allow_other {
+            options.push(MountOption::AllowOther);
+        }
+        // Passthrough opens real files on behalf of the caller, so let the
+        // kernel check the caller against the reported permissions.
+        if self.passthrough || self.allow_other {
+            options.push(MountOption::DefaultPermissions);
+        }
+
+        options
+    }
+
+    /// Returns true if name is the name of the query directory, the all tags
+    /// directory or the control directory, which hide any tag with the same
+    /// name.
+    pub(super) fn is_dir_name(&self, name: &str) -> bool {
+        name == self.query_dir_name || name == self.all_tags_dir_name
+            || name == CONTROL_DIR_NAME
+    }
+
+    /// Returns path with the first matching prefix of map_roots replaced, the
+    /// path that a link to path in the database shows.
+    pub(super) fn map_root<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        self.map_roots.iter()
+            .find_map(|(from, to)| replace_root(path, from, to))
+            .map_or(Cow::Borrowed(path), Cow::Owned)
+    }
+
+    /// Reverses [`MountConfig::map_root`], to find the path in the database
+    /// of a path that a link is created to.
+    pub(super) fn unmap_root<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        self.map_roots.iter()
+            .find_map(|(from, to)| replace_root(path, to, from))
+            .map_or(Cow::Borrowed(path), Cow::Owned)
+    }
+
+    /// Checks that the options can be mounted.
+    pub(super) fn validate(&self) -> Result<(), String> {
+        let names = [&self.query_dir_name, &self.all_tags_dir_name];
+
+        for name in names {
+            if name.is_empty() || name.contains('/') || name == "."
+                || name == ".." || name == CONTROL_DIR_NAME
+            {
+                return Err(format!("invalid directory name \"{name}\"."));
+            }
+        }
+
+        if self.query_dir_name == self.all_tags_dir_name {
+            return Err(String::from("the query directory and the all tags \
+                                     directory must have different names."));
+        }
+
+        if self.max_depth == 0 {
+            return Err(String::from("the maximum depth must be at least \
+                                     one."));
+        }
+
+        for (from, to) in &self.map_roots {
+            if !from.starts_with('/') || !to.starts_with('/') {
+                return Err(format!("cannot map \"{from}\" to \"{to}\", \
+                                    both must be absolute."));
+            }
+        }
+
+        if self.threads == 0 {
+            return Err(String::from("at least one thread is needed to serve \
+                                     requests."));
+        }
+
+        Ok(())
+    }
+}
+
+/// How links to paths with the same file name in one directory are named,
+/// for example two links to path.txt.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicateNames {
+    /// Append the position of the path among the paths with the same name,
+    /// path.txt.0 and path.txt.1.
+    #[default]
+    Suffix,
+    /// Insert the id of the tag mapping before the extension, path (3).txt
+    /// and path (7).txt. A link is only renamed when its path starts or
+    /// stops sharing its name with another path.
+    Counter,
+    /// Prefix the name of the parent directory of the path, dir - path.txt.
+    /// The id of the tag mapping is inserted as well if the parent
+    /// directories have the same name, dir - path (3).txt.
+    Parent,
+    /// Insert a short hash of the full path before the extension of every
+    /// path, path [1a2b3c4d].txt, which never changes.
+    Hash,
+}
+
+impl std::str::FromStr for DuplicateNames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "suffix" => Ok(DuplicateNames::Suffix),
+            "counter" => Ok(DuplicateNames::Counter),
+            "parent" => Ok(DuplicateNames::Parent),
+            "hash" => Ok(DuplicateNames::Hash),
+            _ => Err(format!("\"{s}\" is not one of suffix, counter, parent \
+                              or hash.")),
+        }
+    }
+}
+
+/// Replaces the root from at the start of path with to. Returns None if path
+/// is not below from, which only matches whole components.
+fn replace_root(path: &str, from: &str, to: &str) -> Option<String> {
+    let from = from.trim_end_matches('/');
+    let rest = path.strip_prefix(from)
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))?;
+
+    let mapped = format!("{}{rest}", to.trim_end_matches('/'));
+    Some(if mapped.is_empty() { String::from("/") } else { mapped })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MountConfig;
+    use fuser::MountOption;
+
+    #[test]
+    fn map_root_test() {
+        let config = MountConfig {
+            map_roots: vec![
+                (String::from("/media/hdd"), String::from("/data")),
+                (String::from("/"), String::from("/host/")),
+            ],
+            ..MountConfig::default()
+        };
+
+        assert_eq!(config.map_root("/media/hdd/films"), "/data/films");
+        assert_eq!(config.map_root("/media/hdd"), "/data");
+        assert_eq!(config.map_root("/media/hdd2"), "/host/media/hdd2");
+        assert_eq!(config.unmap_root("/data/films"), "/media/hdd/films");
+        assert_eq!(config.unmap_root("/host/films"), "/films");
+        assert_eq!(config.unmap_root("/films"), "/films");
+    }
+
+    #[test]
+    fn mount_options_test() {
+        let config = MountConfig::default();
+        assert!(!config.mount_options()
+            .contains(&MountOption::DefaultPermissions));
+
+        let config = MountConfig { passthrough: true, ..config };
+        assert!(config.mount_options()
+            .contains(&MountOption::DefaultPermissions));
+
+        let config = MountConfig {
+            passthrough: false,
+            allow_other: true,
+            ..config
+        };
+        assert!(config.mount_options()
+            .contains(&MountOption::DefaultPermissions));
+    }
+}
@@ -0,0 +1,123 @@
+//! files in a hidden directory of the mount that give access to the database,
+//! for scripts that can reach the mount but not the tagfs command.
+
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+
+use anyhow::Result;
+
+use crate::db::Database;
+use crate::path::decode;
+
+/// Name of the control directory. It is not listed in the root directory so
+/// that listing the root only shows tags, but it can always be looked up.
+pub(super) const CONTROL_DIR_NAME: &str = ".tagfs";
+
+/// A file in the control directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum ControlFile {
+    /// Number of tags, paths and mappings in the database.
+    Stats,
+    /// Paths in the database that do not exist.
+    InvalidPaths,
+    /// Stored queries in the same format as `tagfs stored-queries`.
+    StoredQueries,
+    /// Version of tagfs.
+    Version,
+    /// The paths matching the query written to the same handle of this file.
+    Query,
+}
+
+impl ControlFile {
+    /// Every file in the control directory, in the order they are listed.
+    pub(super) const ALL: [ControlFile; 5] = [
+        ControlFile::Stats,
+        ControlFile::InvalidPaths,
+        ControlFile::StoredQueries,
+        ControlFile::Version,
+        ControlFile::Query,
+    ];
+
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            ControlFile::Stats => "stats",
+            ControlFile::InvalidPaths => "invalid-paths",
+            ControlFile::StoredQueries => "stored-queries",
+            ControlFile::Version => "version",
+            ControlFile::Query => "query",
+        }
+    }
+
+    /// Returns true if the file can be written to, only the query file can.
+    pub(super) fn is_writable(self) -> bool {
+        self == ControlFile::Query
+    }
+
+    /// Generates the contents of the file from the database. query is the
+    /// query written to the handle of the query file, or empty if there is
+    /// none. Paths are written as they are on disk, one per line.
+    pub(super) fn contents(self, db: &mut Database, query: &str)
+        -> Result<Vec<u8>>
+    {
+        let mut buf = Vec::new();
+
+        // unwraps are okay here, because we are writing to an in-memory
+        // buffer.
+        match self {
+            ControlFile::Stats => {
+                writeln!(buf, "tags: {}", db.tag_count()?).unwrap();
+                writeln!(buf, "paths: {}", db.path_count()?).unwrap();
+                writeln!(buf, "mappings: {}", db.mapping_count()?).unwrap();
+            }
+            ControlFile::InvalidPaths => {
+                for path in db.invalid_paths()? {
+                    buf.extend_from_slice(decode(&path).as_bytes());
+                    buf.push(b'\n');
+                }
+            }
+            ControlFile::StoredQueries => {
+                for stored_query in db.stored_queries()? {
+                    writeln!(buf, "{stored_query}").unwrap();
+                }
+            }
+            ControlFile::Version => {
+                writeln!(buf, "{}", env!("CARGO_PKG_VERSION")).unwrap();
+            }
+            ControlFile::Query if !query.is_empty() => {
+                for (path, _) in db.query(query, false)? {
+                    buf.extend_from_slice(decode(&path).as_bytes());
+                    buf.push(b'\n');
+                }
+            }
+            ControlFile::Query => {}
+        }
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ControlFile;
+
+    #[test]
+    fn contents_test() {
+        let mut db = crate::db::get_or_create_db(None).unwrap();
+        db.tag("/does/not/exist", "genre", Some("crime")).unwrap();
+        db.tag("/does/not/exist", "favourite", None).unwrap();
+        db.tag("/", "favourite", None).unwrap();
+
+        let mut contents = |file: ControlFile, query: &str|
+            file.contents(&mut db, query).unwrap();
+
+        assert_eq!(contents(ControlFile::Stats, ""),
+            b"tags: 2\npaths: 2\nmappings: 3\n");
+        assert_eq!(contents(ControlFile::InvalidPaths, ""),
+            b"/does/not/exist\n");
+        assert_eq!(contents(ControlFile::Query, "genre==crime"),
+            b"/does/not/exist\n");
+
+        // nothing matches until a query is written.
+        assert_eq!(contents(ControlFile::Query, ""), b"");
+    }
+}
@@ -0,0 +1,126 @@
+//! shares the database between the threads that serve FUSE requests.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard};
+
+use log::{error, info};
+
+use crate::db::Database;
+use crate::fs::lock;
+
+/// Pool of connections to the database. Every write goes through a single
+/// connection, while reads are spread over as many connections as there are
+/// threads reading at once.
+#[derive(Debug)]
+pub(super) struct DatabasePool {
+    /// Connection used for writes.
+    writer: Mutex<Database>,
+    /// Connection only used to check the version of the database, so that
+    /// checking does not wait for a write to finish. None if the database
+    /// cannot be reopened, in which case the writer is used instead.
+    version: Option<Mutex<Database>>,
+    /// Read connections that are not in use, more are opened when every one
+    /// is in use. Empty if the database cannot be reopened, in which case
+    /// reads also go through the writer.
+    readers: Mutex<Vec<Database>>,
+}
+
+impl DatabasePool {
+    pub(super) fn new(db: Database) -> Self {
+        // readers only see the last commit in WAL mode rather than waiting
+        // for the writer to finish. An in-memory database cannot be shared,
+        // so every read goes through the writer instead.
+        let reader = db.enable_wal()
+            .and_then(|()| Ok((db.reopen()?, db.reopen()?)));
+        let (version, readers) = match reader {
+            Ok((version, reader)) => (Some(Mutex::new(version)), vec![reader]),
+            Err(e) => {
+                info!("reading through a single connection: {e:?}");
+                (None, Vec::new())
+            }
+        };
+
+        Self {
+            writer: Mutex::new(db),
+            version,
+            readers: Mutex::new(readers),
+        }
+    }
+
+    /// Returns a connection to read from. Changes made through
+    /// [`DatabasePool::writer`] are visible to it once they are committed.
+    pub(super) fn reader(&self) -> Connection<'_> {
+        let mut readers = lock(&self.readers);
+
+        let Some(db) = readers.pop() else {
+            drop(readers);
+            return Connection::Writer(self.writer());
+        };
+
+        // a spare connection is always kept so that there is one to reopen
+        // the next time every reader is in use.
+        if readers.is_empty() {
+            match db.reopen() {
+                Ok(spare) => readers.push(spare),
+                Err(e) => error!("could not open a read connection: {e:?}"),
+            }
+        }
+
+        Connection::Reader { pool: self, db: Some(db) }
+    }
+
+    /// Returns the connection to write with. Other writers wait until it is
+    /// dropped.
+    pub(super) fn writer(&self) -> MutexGuard<'_, Database> {
+        lock(&self.writer)
+    }
+
+    /// Returns a number that changes whenever the database is modified, see
+    /// [`Database::version`]. The same connection is always used, because
+    /// the numbers of different connections cannot be compared. It never
+    /// writes, so writes through the writer change its number as well.
+    pub(super) fn version(&self) -> anyhow::Result<u64> {
+        match &self.version {
+            Some(db) => lock(db).version(),
+            None => self.writer().version(),
+        }
+    }
+}
+
+/// A connection borrowed from a [`DatabasePool`]. Read connections go back
+/// to the pool when they are dropped.
+pub(super) enum Connection<'a> {
+    Reader { pool: &'a DatabasePool, db: Option<Database> },
+    Writer(MutexGuard<'a, Database>),
+}
+
+impl Deref for Connection<'_> {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        match self {
+            // db is only taken when the connection is dropped.
+            Connection::Reader { db, .. } => db.as_ref().unwrap(),
+            Connection::Writer(db) => db,
+        }
+    }
+}
+
+impl DerefMut for Connection<'_> {
+    fn deref_mut(&mut self) -> &mut Database {
+        match self {
+            Connection::Reader { db, .. } => db.as_mut().unwrap(),
+            Connection::Writer(db) => db,
+        }
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        if let Connection::Reader { pool, db } = self {
+            if let Some(db) = db.take() {
+                lock(&pool.readers).push(db);
+            }
+        }
+    }
+}
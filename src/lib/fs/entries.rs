@@ -1,26 +1,23 @@
 //! stores inodes and their relationships.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
-use log::error;
-use once_cell::sync::Lazy;
 
-use crate::fs::INodeGenerator;
-use crate::fs::MOUNT_TIME;
+use crate::db::TagValuePair;
+use crate::fs::control::{ControlFile, CONTROL_DIR_NAME};
+use crate::fs::inode_generator;
+use crate::fs::{MountConfig, MOUNT_TIME};
 
-static CURRENT_UID: Lazy<libc::uid_t> = Lazy::new(|| {
-    unsafe { libc::getuid() }
-});
-static CURRENT_GID: Lazy<libc::gid_t> = Lazy::new(|| {
-    unsafe { libc::getgid() }
-});
-
-/// Hardcoded name of the query directory.
-const QUERY_DIR_NAME: &str = "?";
-
-/// Hardcoded name of the query directory.
-const ALL_TAGS_DIR_NAME: &str = "tags";
+/// Returns a stable inode for the entry identified by key within the parent
+/// inode, that is not already taken by another entry.
+fn new_inode(attrs: &HashMap<u64, Entry>, parent_inode: u64, key: &str)
+    -> u64
+{
+    inode_generator::generate(parent_inode, key,
+        |inode| attrs.contains_key(&inode))
+}
 
 /// Each inode is one and only one of the types described in [`Entry`].
 #[derive(Debug)]
@@ -37,13 +34,22 @@ enum Entry {
     QueryResultDir {
         display_name: String, query: String, attr: FileAttr,
     },
-    /// Path: /tag
+    /// Path: /tag or nested below another tag directory, for example
+    /// /tag/value/tag.
     TagDir {
-        name: String, attr: FileAttr,
+        name: String,
+        /// Tag value pairs of the enclosing directories, empty at the top
+        /// level.
+        filter: Vec<TagValuePair>,
+        attr: FileAttr,
     },
-    /// Path: /tag/value
+    /// Path: /tag/value or nested below another tag directory, for example
+    /// /tag/value/tag/value.
     ValueDir {
-        display_name: String, value: String, tag: String, attr: FileAttr,
+        display_name: String, value: String, tag: String,
+        /// Tag value pairs of the enclosing directories, not including tag.
+        filter: Vec<TagValuePair>,
+        attr: FileAttr,
     },
     /// Symlink to a real file.
     Link {
@@ -64,6 +70,19 @@ enum Entry {
     AllTagsTerminal {
         path: String, name: String, attr: FileAttr,
     },
+    /// Real file or directory below a link to a directory, only used in
+    /// passthrough mode. Path: /tag/link/some/real/file
+    Passthrough {
+        path: String, name: String, attr: FileAttr,
+    },
+    /// ControlDir inode - should only ever be one. Path: /.tagfs
+    ControlDir {
+        attr: FileAttr,
+    },
+    /// File in the control directory. Path: /.tagfs/stats
+    ControlFile {
+        file: ControlFile, attr: FileAttr,
+    },
 }
 
 /// public type enum to avoid exposing the entry enum.
@@ -78,26 +97,50 @@ pub enum EntryType {
     AllTagsDir,
     AllTagsIntermediate,
     AllTagsTerminal,
+    Passthrough,
+    ControlDir,
+    ControlFile,
 }
 
 #[derive(Debug)]
 /// Tracks the mapping of inodes to entries.
 pub struct Entries {
-    /// generates a unique inode.
-    inode_generator: INodeGenerator,
-
     /// inode -> Entry
     attrs: HashMap<u64, Entry>,
 
     /// NOTE: we are duplicating data between attrs and names (String). \
     /// parent_inode -> (name -> inode)
     names: HashMap<u64, HashMap<String, u64>>,
+
+    /// inode -> parent_inode
+    parents: HashMap<u64, u64>,
+
+    /// inode -> number of lookups the kernel holds on the inode. Inodes
+    /// without any lookups are not present.
+    lookups: HashMap<u64, u64>,
+
+    /// parent_inode -> database version its children were last listed at.
+    listed_versions: HashMap<u64, u64>,
+
+    /// inode -> when its attributes were last replaced by those of a real
+    /// file.
+    refreshed: HashMap<u64, Instant>,
+
+    /// Name of the query directory.
+    query_dir_name: String,
+    /// Name of the all tags directory.
+    all_tags_dir_name: String,
+    dir_perm: u16,
+    file_perm: u16,
+    uid: u32,
+    gid: u32,
 }
 
 impl Entries {
 
-    /// Create a new entries struct and initialise it with the root inode.
-    pub fn new() -> Self {
+    /// Create a new entries struct and initialise it with the root inode. The
+    /// names, permissions and owner of the entries are taken from config.
+    pub fn new(config: &MountConfig) -> Self {
         let mut attrs = HashMap::new();
         attrs.insert(FUSE_ROOT_ID, Entry::Root {
             attr: FileAttr {
@@ -109,18 +152,27 @@ impl Entries {
                 ctime: *MOUNT_TIME,
                 crtime: *MOUNT_TIME,
                 kind: FileType::Directory,
-                perm: 0o755,
+                perm: config.dir_perm,
                 nlink: 1,
-                uid: *CURRENT_UID,
-                gid: *CURRENT_GID,
+                uid: config.uid,
+                gid: config.gid,
                 rdev: 0,
                 flags: 0,
                 blksize: 512,
             }});
 
         Self {
-            inode_generator: INodeGenerator::new(),
             names: HashMap::new(),
+            parents: HashMap::new(),
+            lookups: HashMap::new(),
+            listed_versions: HashMap::new(),
+            refreshed: HashMap::new(),
+            query_dir_name: config.query_dir_name.clone(),
+            all_tags_dir_name: config.all_tags_dir_name.clone(),
+            dir_perm: config.dir_perm,
+            file_perm: config.file_perm,
+            uid: config.uid,
+            gid: config.gid,
             attrs,
         }
     }
@@ -129,11 +181,12 @@ impl Entries {
     /// not exist.
     pub fn get_or_create_all_tags_dir(&mut self) -> u64 {
         let children = self.names.entry(FUSE_ROOT_ID).or_default();
-        if let Some(inode) = children.get(ALL_TAGS_DIR_NAME) {
+        if let Some(inode) = children.get(&self.all_tags_dir_name) {
             *inode
         } else {
-            let inode = self.inode_generator.next();
-            children.insert(ALL_TAGS_DIR_NAME.to_string(), inode);
+            let inode = new_inode(&self.attrs, FUSE_ROOT_ID, "dir:tags");
+            children.insert(self.all_tags_dir_name.clone(), inode);
+            self.parents.insert(inode, FUSE_ROOT_ID);
 
             self.attrs.insert(inode, Entry::AllTagsDir {
                 attr: FileAttr {
@@ -145,10 +198,10 @@ impl Entries {
                     ctime: *MOUNT_TIME,
                     crtime: *MOUNT_TIME,
                     kind: FileType::Directory,
-                    perm: 0o755,
+                    perm: self.dir_perm,
                     nlink: 1,
-                    uid: *CURRENT_UID,
-                    gid: *CURRENT_GID,
+                    uid: self.uid,
+                    gid: self.gid,
                     rdev: 0,
                     flags: 0,
                     blksize: 512,
@@ -169,14 +222,18 @@ impl Entries {
         };
 
         if let Some(inode) = inode {
-            if self.get_attr(inode).kind == FileType::Directory {
+            if self.get_attr(inode).is_some_and(|attr|
+                attr.kind == FileType::Directory)
+            {
                 return inode;
             }
         }
 
         let children = self.names.entry(parent_inode).or_default();
-        let inode = self.inode_generator.next();
+        let inode = new_inode(&self.attrs, parent_inode,
+            &format!("path:{name}"));
         children.insert(name.to_string(), inode);
+        self.parents.insert(inode, parent_inode);
 
         self.attrs.insert(inode, Entry::AllTagsIntermediate {
             name: name.to_string(),
@@ -190,10 +247,10 @@ impl Entries {
                 ctime: *MOUNT_TIME,
                 crtime: *MOUNT_TIME,
                 kind: FileType::Directory,
-                perm: 0o755,
+                perm: self.dir_perm,
                 nlink: 1,
-                uid: *CURRENT_UID,
-                gid: *CURRENT_GID,
+                uid: self.uid,
+                gid: self.gid,
                 rdev: 0,
                 flags: 0,
                 blksize: 512,
@@ -213,14 +270,18 @@ impl Entries {
         };
 
         if let Some(inode) = inode {
-            if self.get_attr(inode).kind == FileType::RegularFile {
+            if self.get_attr(inode).is_some_and(|attr|
+                attr.kind == FileType::RegularFile)
+            {
                 return inode;
             }
         }
 
         let children = self.names.entry(parent_inode).or_default();
-        let inode = self.inode_generator.next();
+        let inode = new_inode(&self.attrs, parent_inode,
+            &format!("tags:{name}"));
         children.insert(name.to_string(), inode);
+        self.parents.insert(inode, parent_inode);
 
         self.attrs.insert(inode, Entry::AllTagsTerminal {
             name: name.to_string(),
@@ -234,10 +295,10 @@ impl Entries {
                 ctime: *MOUNT_TIME,
                 crtime: *MOUNT_TIME,
                 kind: FileType::RegularFile,
-                perm: 0o444,
+                perm: self.file_perm,
                 nlink: 1,
-                uid: *CURRENT_UID,
-                gid: *CURRENT_GID,
+                uid: self.uid,
+                gid: self.gid,
                 rdev: 0,
                 flags: 0,
                 blksize: 512,
@@ -250,11 +311,12 @@ impl Entries {
     /// exist.
     pub fn get_or_create_query_directory(&mut self) -> u64 {
         let children = self.names.entry(FUSE_ROOT_ID).or_default();
-        if let Some(inode) = children.get(QUERY_DIR_NAME) {
+        if let Some(inode) = children.get(&self.query_dir_name) {
             *inode
         } else {
-            let inode = self.inode_generator.next();
-            children.insert(QUERY_DIR_NAME.to_string(), inode);
+            let inode = new_inode(&self.attrs, FUSE_ROOT_ID, "dir:?");
+            children.insert(self.query_dir_name.clone(), inode);
+            self.parents.insert(inode, FUSE_ROOT_ID);
 
             self.attrs.insert(inode, Entry::QueryDir {
                 attr: FileAttr {
@@ -266,10 +328,44 @@ impl Entries {
                     ctime: *MOUNT_TIME,
                     crtime: *MOUNT_TIME,
                     kind: FileType::Directory,
-                    perm: 0o755,
+                    perm: self.dir_perm,
+                    nlink: 1,
+                    uid: self.uid,
+                    gid: self.gid,
+                    rdev: 0,
+                    flags: 0,
+                    blksize: 512,
+            }});
+
+            inode
+        }
+    }
+
+    /// Returns the inode of the control directory, or creates it if it does
+    /// not exist.
+    pub fn get_or_create_control_dir(&mut self) -> u64 {
+        let children = self.names.entry(FUSE_ROOT_ID).or_default();
+        if let Some(inode) = children.get(CONTROL_DIR_NAME) {
+            *inode
+        } else {
+            let inode = new_inode(&self.attrs, FUSE_ROOT_ID, "dir:.tagfs");
+            children.insert(CONTROL_DIR_NAME.to_string(), inode);
+            self.parents.insert(inode, FUSE_ROOT_ID);
+
+            self.attrs.insert(inode, Entry::ControlDir {
+                attr: FileAttr {
+                    ino: inode,
+                    size: 0,
+                    blocks: 0,
+                    atime: *MOUNT_TIME,
+                    mtime: *MOUNT_TIME,
+                    ctime: *MOUNT_TIME,
+                    crtime: *MOUNT_TIME,
+                    kind: FileType::Directory,
+                    perm: self.dir_perm,
                     nlink: 1,
-                    uid: *CURRENT_UID,
-                    gid: *CURRENT_GID,
+                    uid: self.uid,
+                    gid: self.gid,
                     rdev: 0,
                     flags: 0,
                     blksize: 512,
@@ -279,6 +375,51 @@ impl Entries {
         }
     }
 
+    /// Returns the inode of a file in the control directory, or creates it if
+    /// it does not exist. Only the writable files can be written to by the
+    /// owner.
+    pub fn get_or_create_control_file(&mut self, parent_inode: u64,
+                                      file: ControlFile) -> u64
+    {
+        let children = self.names.entry(parent_inode).or_default();
+        if let Some(inode) = children.get(file.name()) {
+            return *inode;
+        }
+
+        let inode = new_inode(&self.attrs, parent_inode,
+            &format!("control:{}", file.name()));
+        children.insert(file.name().to_string(), inode);
+        self.parents.insert(inode, parent_inode);
+
+        let perm = if file.is_writable() {
+            self.file_perm
+        } else {
+            self.file_perm & !0o222
+        };
+
+        self.attrs.insert(inode, Entry::ControlFile {
+            file,
+            attr: FileAttr {
+                ino: inode,
+                size: 512,
+                blocks: 1,
+                atime: *MOUNT_TIME,
+                mtime: *MOUNT_TIME,
+                ctime: *MOUNT_TIME,
+                crtime: *MOUNT_TIME,
+                kind: FileType::RegularFile,
+                perm,
+                nlink: 1,
+                uid: self.uid,
+                gid: self.gid,
+                rdev: 0,
+                flags: 0,
+                blksize: 512,
+        }});
+
+        inode
+    }
+
     /// Returns the inode of a query result directory, or creates it if it does
     /// not exist.
     pub fn get_or_create_query_result_dir(&mut self, query: &str, name: &str)
@@ -289,8 +430,12 @@ impl Entries {
         if let Some(inode) = children.get(name) {
             *inode
         } else {
-            let inode = self.inode_generator.next();
+            // a stored query is found under several names, which each have
+            // an inode of their own.
+            let inode = new_inode(&self.attrs, query_dir_inode,
+                &format!("query:{name}"));
             children.insert(name.to_string(), inode);
+            self.parents.insert(inode, query_dir_inode);
 
             self.attrs.insert(inode, Entry::QueryResultDir {
                 display_name: name.to_string(),
@@ -304,10 +449,10 @@ impl Entries {
                     ctime: *MOUNT_TIME,
                     crtime: *MOUNT_TIME,
                     kind: FileType::Directory,
-                    perm: 0o755,
+                    perm: self.dir_perm,
                     nlink: 1,
-                    uid: *CURRENT_UID,
-                    gid: *CURRENT_GID,
+                    uid: self.uid,
+                    gid: self.gid,
                     rdev: 0,
                     flags: 0,
                     blksize: 512,
@@ -320,17 +465,22 @@ impl Entries {
     /// Returns the inode of a parent name pair, or creates it if it does not
     /// exist.
     pub fn get_or_create_tag_directory(&mut self, parent_inode: u64,
-                                       name: &str) -> u64
+                                       name: &str, tag_id: i64) -> u64
     {
-        let children = self.names.entry(parent_inode).or_default();
-        if let Some(inode) = children.get(name) {
-            *inode
+        if let Some(inode) = self.try_get_inode(parent_inode, name) {
+            inode
         } else {
-            let inode = self.inode_generator.next();
-            children.insert(name.to_string(), inode);
+            let filter = self.get_child_filter(parent_inode);
+
+            let inode = new_inode(&self.attrs, parent_inode,
+                &format!("tag:{tag_id}"));
+            self.names.entry(parent_inode).or_default()
+                .insert(name.to_string(), inode);
+            self.parents.insert(inode, parent_inode);
 
             self.attrs.insert(inode, Entry::TagDir {
                 name: name.to_string(),
+                filter,
                 attr: FileAttr {
                     ino: inode,
                     size: 0,
@@ -340,10 +490,10 @@ impl Entries {
                     ctime: *MOUNT_TIME,
                     crtime: *MOUNT_TIME,
                     kind: FileType::Directory,
-                    perm: 0o755,
+                    perm: self.dir_perm,
                     nlink: 1,
-                    uid: *CURRENT_UID,
-                    gid: *CURRENT_GID,
+                    uid: self.uid,
+                    gid: self.gid,
                     rdev: 0,
                     flags: 0,
                     blksize: 512,
@@ -362,13 +512,17 @@ impl Entries {
         if let Some(inode) = children.get(name) {
             *inode
         } else {
-            let inode = self.inode_generator.next();
+            let inode = new_inode(&self.attrs, parent_inode,
+                &format!("value:{value}"));
             children.insert(name.to_string(), inode);
+            self.parents.insert(inode, parent_inode);
 
             self.attrs.insert(inode, Entry::ValueDir {
                 display_name: name.to_string(),
                 value: value.to_string(),
-                tag: self.get_name(parent_inode).to_string(),
+                tag: self.get_name(parent_inode).unwrap_or_default()
+                    .to_string(),
+                filter: self.get_filter(parent_inode).to_vec(),
                 attr: FileAttr {
                     ino: inode,
                     size: 0,
@@ -378,10 +532,10 @@ impl Entries {
                     ctime: *MOUNT_TIME,
                     crtime: *MOUNT_TIME,
                     kind: FileType::Directory,
-                    perm: 0o755,
+                    perm: self.dir_perm,
                     nlink: 1,
-                    uid: *CURRENT_UID,
-                    gid: *CURRENT_GID,
+                    uid: self.uid,
+                    gid: self.gid,
                     rdev: 0,
                     flags: 0,
                     blksize: 512,
@@ -396,9 +550,11 @@ impl Entries {
                        target: u64, target_len: u64) -> u64
     {
         let children = self.names.entry(parent_inode).or_default();
-        let inode = self.inode_generator.next();
+        let inode = new_inode(&self.attrs, parent_inode,
+            &format!("link:{target}"));
 
         children.insert(name.to_string(), inode);
+        self.parents.insert(inode, parent_inode);
 
         self.attrs.insert(inode, Entry::Link {
             name: name.to_string(),
@@ -414,8 +570,8 @@ impl Entries {
                 kind: FileType::Symlink,
                 perm: 0o755,
                 nlink: 1,
-                uid: *CURRENT_UID,
-                gid: *CURRENT_GID,
+                uid: self.uid,
+                gid: self.gid,
                 rdev: 0,
                 flags: 0,
                 blksize: 512,
@@ -424,6 +580,233 @@ impl Entries {
         inode
     }
 
+    /// Returns the inode of a real file or directory below parent_inode, or
+    /// creates it if it does not exist. The attributes of an existing entry
+    /// are replaced by attr.
+    pub fn get_or_create_passthrough(&mut self, parent_inode: u64,
+                                     name: &str, path: &str, attr: FileAttr)
+        -> u64
+    {
+        if let Some(inode) = self.try_get_inode(parent_inode, name) {
+            if let Some(Entry::Passthrough { .. }) = self.attrs.get(&inode) {
+                self.set_attr(inode, attr);
+                return inode;
+            }
+        }
+
+        let children = self.names.entry(parent_inode).or_default();
+        let inode = new_inode(&self.attrs, parent_inode,
+            &format!("real:{name}"));
+        children.insert(name.to_string(), inode);
+        self.parents.insert(inode, parent_inode);
+
+        self.attrs.insert(inode, Entry::Passthrough {
+            name: name.to_string(),
+            path: path.to_string(),
+            attr: FileAttr { ino: inode, ..attr },
+        });
+
+        inode
+    }
+
+    /// Remove a child from the directory listing of its parent and return its
+    /// inode if it existed.
+    ///
+    /// The entry itself is kept until the kernel forgets the inode, if the
+    /// kernel does not hold a lookup on it the entry is evicted straight away.
+    pub fn remove_child(&mut self, parent_inode: u64, name: &str)
+        -> Option<u64>
+    {
+        let inode = self.detach_child(parent_inode, name)?;
+
+        if !self.lookups.contains_key(&inode) {
+            self.evict(inode);
+        }
+
+        Some(inode)
+    }
+
+    /// Remove the children of a directory that are not in listed, for example
+    /// because they no longer exist in the database.
+    pub fn retain_children(&mut self, parent_inode: u64, listed: &[u64]) {
+        let listed: HashSet<_> = listed.iter().collect();
+        let unlisted: Vec<_> = self.names.get(&parent_inode).into_iter()
+            .flatten()
+            .filter(|(_, inode)| !listed.contains(inode))
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in unlisted {
+            self.remove_child(parent_inode, &name);
+        }
+    }
+
+    /// Record that the children of a directory were listed in full at a
+    /// particular database version.
+    pub fn set_listed_version(&mut self, inode: u64, version: u64) {
+        self.listed_versions.insert(inode, version);
+    }
+
+    /// Returns true if the children of a directory were listed in full at a
+    /// particular database version, i.e. they are up to date.
+    pub fn is_listed_at(&self, inode: u64, version: u64) -> bool {
+        self.listed_versions.get(&inode) == Some(&version)
+    }
+
+    /// Record that the kernel has looked up an inode. Each lookup is balanced
+    /// by a call to [`Entries::forget`].
+    pub fn lookup(&mut self, inode: u64) {
+        *self.lookups.entry(inode).or_default() += 1;
+    }
+
+    /// Forget nlookup lookups of an inode. Once the kernel holds no lookups on
+    /// the inode its entry is evicted, it will be recreated if it is looked up
+    /// again.
+    pub fn forget(&mut self, inode: u64, nlookup: u64) {
+        let Some(lookups) = self.lookups.get_mut(&inode) else { return };
+
+        *lookups = lookups.saturating_sub(nlookup);
+        if *lookups == 0 {
+            self.lookups.remove(&inode);
+            self.evict(inode);
+        }
+    }
+
+    /// Remove a child from the directory listing of its parent without
+    /// evicting its entry.
+    fn detach_child(&mut self, parent_inode: u64, name: &str) -> Option<u64> {
+        self.names.get_mut(&parent_inode)
+            .and_then(|children| children.remove(name))
+    }
+
+    /// Drop an entry along with any of its children the kernel does not hold
+    /// a lookup on. Children that are still looked up are evicted when they
+    /// are forgotten.
+    fn evict(&mut self, inode: u64) {
+        if inode == FUSE_ROOT_ID {
+            return;
+        }
+
+        let name = self.get_name(inode).map(str::to_string);
+
+        if let (Some(parent_inode), Some(name))
+            = (self.parents.remove(&inode), name)
+        {
+            let children = self.names.get_mut(&parent_inode);
+            if let Some(children) = children {
+                if children.get(&name) == Some(&inode) {
+                    children.remove(&name);
+
+                    // the parent must be listed again to recreate the entry.
+                    self.listed_versions.remove(&parent_inode);
+                }
+            }
+        }
+
+        self.attrs.remove(&inode);
+        self.listed_versions.remove(&inode);
+        self.refreshed.remove(&inode);
+
+        let children = self.names.remove(&inode).into_iter().flatten();
+        for (_, child_inode) in children {
+            if !self.lookups.contains_key(&child_inode) {
+                self.evict(child_inode);
+            }
+        }
+    }
+
+    /// Move an entry to a new parent and name and return its inode if it
+    /// existed.
+    ///
+    /// The name stored in the entry is updated to match. An
+    /// [`Entry::ValueDir`] also takes the new name as its value and the
+    /// children of an [`Entry::TagDir`] are updated to refer to the new tag.
+    /// Nested tag directories below a renamed directory are detached as their
+    /// filters refer to the old name.
+    pub fn rename(&mut self, parent_inode: u64, name: &str,
+                  new_parent_inode: u64, new_name: &str) -> Option<u64>
+    {
+        let inode = self.detach_child(parent_inode, name)?;
+        self.names.entry(new_parent_inode).or_default()
+            .insert(new_name.to_string(), inode);
+        self.parents.insert(inode, new_parent_inode);
+
+        if matches!(self.attrs.get(&inode),
+                Some(Entry::TagDir { .. } | Entry::ValueDir { .. })) {
+            self.detach_nested_dirs(inode);
+        }
+
+        match self.attrs.get_mut(&inode) {
+            Some(Entry::TagDir { name, .. }) => {
+                *name = new_name.to_string();
+
+                let children = self.names.get(&inode).into_iter()
+                    .flat_map(HashMap::values);
+                for child_inode in children {
+                    let child = self.attrs.get_mut(child_inode);
+                    if let Some(Entry::ValueDir { tag, .. }) = child {
+                        *tag = new_name.to_string();
+                    }
+                }
+            }
+            Some(Entry::ValueDir { display_name, value, .. }) => {
+                *display_name = new_name.to_string();
+                *value = new_name.to_string();
+            }
+            Some(Entry::QueryResultDir { display_name: name, .. }
+                | Entry::Link { name, .. }
+                | Entry::AllTagsIntermediate { name, .. }
+                | Entry::AllTagsTerminal { name, .. }) => {
+                *name = new_name.to_string();
+            }
+            _ => {}
+        }
+
+        Some(inode)
+    }
+
+    /// Detach the nested tag directories below a tag or value directory.
+    fn detach_nested_dirs(&mut self, inode: u64) {
+        let children: Vec<_> = self.names.get(&inode).into_iter()
+            .flatten()
+            .map(|(name, child_inode)| (name.clone(), *child_inode))
+            .collect();
+
+        for (name, child_inode) in children {
+            match self.attrs.get(&child_inode) {
+                Some(Entry::TagDir { .. }) => {
+                    self.remove_child(inode, &name);
+                }
+                Some(Entry::ValueDir { .. }) =>
+                    self.detach_nested_dirs(child_inode),
+                _ => {}
+            }
+        }
+    }
+
+    /// Point a link at a different TagMappingID.
+    pub fn set_link_target(&mut self, inode: u64, tag_mapping_id: u64) {
+        if let Some(Entry::Link { target, .. }) = self.attrs.get_mut(&inode) {
+            *target = tag_mapping_id;
+        }
+    }
+
+    /// Returns the parent and name of every child, for example to tell the
+    /// kernel to forget them.
+    pub fn children(&self) -> Vec<(u64, String)> {
+        self.names.iter()
+            .flat_map(|(parent, children)| children.keys()
+                .map(|name| (*parent, name.clone())))
+            .collect()
+    }
+
+    /// Returns the inodes of the children of a directory.
+    pub fn child_inodes(&self, parent_inode: u64) -> Vec<u64> {
+        self.names.get(&parent_inode).into_iter()
+            .flat_map(|children| children.values().copied())
+            .collect()
+    }
+
     /// Attempt to return the inode of the requested entry, if it cannot be
     /// found return None.
     pub fn try_get_inode(&self, parent_inode: u64, name: &str)
@@ -451,149 +834,265 @@ impl Entries {
             })
     }
 
+    /// Returns the tag value pairs a tag or value directory is nested within,
+    /// empty for any other inode.
+    pub fn get_filter(&self, inode: u64) -> &[TagValuePair] {
+        match self.attrs.get(&inode) {
+            Some(Entry::TagDir { filter, .. }
+                | Entry::ValueDir { filter, .. }) => filter,
+            _ => &[],
+        }
+    }
+
+    /// Returns true if the inode is a tag or value directory nested below
+    /// another tag directory.
+    pub fn is_nested(&self, inode: u64) -> bool {
+        !self.get_filter(inode).is_empty()
+    }
+
+    /// Get the number of components in the path of an inode, which is zero for
+    /// the root.
+    pub fn get_depth(&self, inode: u64) -> usize {
+        std::iter::successors(Some(inode), |inode| self.parents.get(inode)
+            .copied())
+            .take_while(|inode| *inode != FUSE_ROOT_ID)
+            .count()
+    }
+
+    /// Returns the tag value pairs that the children of an inode are nested
+    /// within, i.e. the filter of the inode plus its own tag value pair.
+    pub fn get_child_filter(&self, inode: u64) -> Vec<TagValuePair> {
+        match self.attrs.get(&inode) {
+            Some(Entry::TagDir { name, filter, .. }) => {
+                let mut filter = filter.clone();
+                filter.push(TagValuePair {
+                    tag: name.clone(), value: None
+                });
+                filter
+            }
+            Some(Entry::ValueDir { tag, value, filter, .. }) => {
+                let mut filter = filter.clone();
+                filter.push(TagValuePair {
+                    tag: tag.clone(), value: Some(value.clone())
+                });
+                filter
+            }
+            _ => Vec::new(),
+        }
+    }
+
     /// Get the parent tag associated with a value by inode.
     ///
-    /// This is only valid when called with an [`Entry::ValueDir`] inode.
-    pub fn get_parent_tag(&self, inode: u64) -> &str {
+    /// Returns None if the inode is not an [`Entry::ValueDir`].
+    pub fn get_parent_tag(&self, inode: u64) -> Option<&str> {
         if let Some(Entry::ValueDir { tag, .. }) = self.attrs.get(&inode) {
-            tag
+            Some(tag)
         } else {
-            error!("tried to lookup parent tag of non ValueDir entry: \
-                    {inode:#x?}.");
-            panic!("tried to lookup parent tag of non ValueDir entry: \
-                    {inode:#x?}.");
+            None
         }
     }
 
     /// Get the target of a link by inode.
+    ///
+    /// Returns None if the inode is not an [`Entry::Link`].
     pub fn get_link_target(&self, inode: u64) -> Option<u64> {
-        self.attrs.get(&inode).map(|entry| {
-            match entry {
-                Entry::Link { target, .. } => *target,
-                _ => panic!("programming error - directory is not a link \
-                             and has no target."),
-            }
-        })
+        if let Some(Entry::Link { target, .. }) = self.attrs.get(&inode) {
+            Some(*target)
+        } else {
+            None
+        }
+    }
+
+    /// Get the file in the control directory an inode refers to.
+    ///
+    /// Returns None if the inode is not an [`Entry::ControlFile`].
+    pub fn get_control_file(&self, inode: u64) -> Option<ControlFile> {
+        if let Some(Entry::ControlFile { file, .. }) = self.attrs.get(&inode) {
+            Some(*file)
+        } else {
+            None
+        }
     }
 
     /// Get the query related to a [`Entry::QueryResultDir`].
-    pub fn get_query(&self, inode: u64) -> &str {
+    ///
+    /// Returns None if the inode is not an [`Entry::QueryResultDir`].
+    pub fn get_query(&self, inode: u64) -> Option<&str> {
         let attr = self.attrs.get(&inode);
         if let Some(Entry::QueryResultDir { query, .. }) = attr {
-            query
+            Some(query)
         } else {
-            error!("tried to lookup query of non QueryResultDir entry: \
-                    {inode:#x?}.");
-            panic!("tried to lookup query of non QueryResultDir entry: \
-                    {inode:#x?}.");
+            None
         }
     }
 
     /// Get the attributes for an inode.
     ///
-    /// To call this function with an inode that does not exist is a
-    /// programming error, therefore we panic if it does not exist.
-    pub fn get_attr(&self, inode: u64) -> &FileAttr {
-        if let Some(entry) = self.attrs.get(&inode) {
-            match entry {
-                Entry::Root { attr }
-                | Entry::QueryDir { attr }
-                | Entry::QueryResultDir { attr, .. }
-                | Entry::TagDir { attr, .. }
-                | Entry::ValueDir { attr, .. }
-                | Entry::AllTagsDir { attr, .. }
-                | Entry::AllTagsIntermediate { attr, .. }
-                | Entry::AllTagsTerminal { attr, .. }
-                | Entry::Link { attr, .. } => attr,
-            }
-        } else {
-            error!("tried to lookup non existent inode: {inode:#x?}.");
-            panic!("tried to lookup non existent inode: {inode:#x?}.");
+    /// Returns None if the inode does not exist, for example because it was
+    /// evicted or never existed.
+    pub fn get_attr(&self, inode: u64) -> Option<&FileAttr> {
+        self.attrs.get(&inode).map(|entry| match entry {
+            Entry::Root { attr }
+            | Entry::QueryDir { attr }
+            | Entry::QueryResultDir { attr, .. }
+            | Entry::TagDir { attr, .. }
+            | Entry::ValueDir { attr, .. }
+            | Entry::AllTagsDir { attr, .. }
+            | Entry::AllTagsIntermediate { attr, .. }
+            | Entry::AllTagsTerminal { attr, .. }
+            | Entry::Passthrough { attr, .. }
+            | Entry::ControlDir { attr }
+            | Entry::ControlFile { attr, .. }
+            | Entry::Link { attr, .. } => attr,
+        })
+    }
+
+    /// Replace the attributes of an inode with those of a real file, keeping
+    /// its inode number.
+    pub fn set_attr(&mut self, inode: u64, new_attr: FileAttr) {
+        if let Some(attr) = self.get_attr_mut(inode) {
+            *attr = FileAttr { ino: inode, ..new_attr };
+            self.refreshed.insert(inode, Instant::now());
         }
     }
 
+    /// Returns true if the attributes of an inode were replaced by those of a
+    /// real file less than ttl ago.
+    pub fn is_attr_fresh(&self, inode: u64, ttl: Duration) -> bool {
+        self.refreshed.get(&inode)
+            .is_some_and(|refreshed| refreshed.elapsed() < ttl)
+    }
+
+    fn get_attr_mut(&mut self, inode: u64) -> Option<&mut FileAttr> {
+        self.attrs.get_mut(&inode).map(|entry| match entry {
+            Entry::Root { attr }
+            | Entry::QueryDir { attr }
+            | Entry::QueryResultDir { attr, .. }
+            | Entry::TagDir { attr, .. }
+            | Entry::ValueDir { attr, .. }
+            | Entry::AllTagsDir { attr, .. }
+            | Entry::AllTagsIntermediate { attr, .. }
+            | Entry::AllTagsTerminal { attr, .. }
+            | Entry::Passthrough { attr, .. }
+            | Entry::ControlDir { attr }
+            | Entry::ControlFile { attr, .. }
+            | Entry::Link { attr, .. } => attr,
+        })
+    }
+
     /// Get the name of an inode.
     ///
-    /// To call this function with an inode that does not exist is a
-    /// programming error, therefore we panic if it does not exist.
-    pub fn get_name(&self, inode: u64) -> &str {
-        if let Some(entry) = self.attrs.get(&inode) {
-            match entry {
-                Entry::Root { .. } => "/",
-                Entry::QueryDir { .. } => QUERY_DIR_NAME,
-                Entry::AllTagsDir { .. } => ALL_TAGS_DIR_NAME,
-
-                Entry::QueryResultDir { display_name: name, .. }
-                | Entry::TagDir { name, .. }
-                | Entry::ValueDir { display_name: name, .. }
-                | Entry::AllTagsIntermediate { name, .. }
-                | Entry::AllTagsTerminal { name, .. }
-                | Entry::Link { name, .. } => name,
-            }
-        } else {
-            error!("tried to lookup non existent inode: {inode:#x?}.");
-            panic!("tried to lookup non existent inode: {inode:#x?}.");
-        }
+    /// Returns None if the inode does not exist.
+    pub fn get_name(&self, inode: u64) -> Option<&str> {
+        self.attrs.get(&inode).map(|entry| match entry {
+            Entry::Root { .. } => "/",
+            Entry::QueryDir { .. } => self.query_dir_name.as_str(),
+            Entry::AllTagsDir { .. } => self.all_tags_dir_name.as_str(),
+            Entry::ControlDir { .. } => CONTROL_DIR_NAME,
+            Entry::ControlFile { file, .. } => file.name(),
+
+            Entry::QueryResultDir { display_name: name, .. }
+            | Entry::TagDir { name, .. }
+            | Entry::ValueDir { display_name: name, .. }
+            | Entry::AllTagsIntermediate { name, .. }
+            | Entry::AllTagsTerminal { name, .. }
+            | Entry::Passthrough { name, .. }
+            | Entry::Link { name, .. } => name.as_str(),
+        })
     }
 
-    /// Get the path of an inode in the [`Entry::AllTagsDir`] hierarchy.
+    /// Get the path of an inode in the [`Entry::AllTagsDir`] hierarchy, or the
+    /// real path of an [`Entry::Passthrough`].
     ///
-    /// To call this function with an inode that does not exist is a
-    /// programming error, therefore we panic if it does not exist.
-    /// Additionally it is a programming error to call this function with an
-    /// inode type that is not an [`Entry::AllTagsDir`] or an
-    /// [`Entry::AllTagsIntermediate`], therefore we also panic in this case.
-    pub fn get_path(&self, inode: u64) -> &str {
-        if let Some(entry) = self.attrs.get(&inode) {
-            if let Entry::AllTagsDir { .. } = entry {
-                return "/";
-            } else if let Entry::AllTagsIntermediate { path, .. } = entry {
-                return path;
-            } else if let Entry::AllTagsTerminal { path, .. } = entry {
-                return path;
-            }
+    /// Returns None if the inode does not exist or if it is not an
+    /// [`Entry::AllTagsDir`], an [`Entry::AllTagsIntermediate`], an
+    /// [`Entry::AllTagsTerminal`] or an [`Entry::Passthrough`].
+    pub fn get_path(&self, inode: u64) -> Option<&str> {
+        match self.attrs.get(&inode) {
+            Some(Entry::AllTagsDir { .. }) => Some("/"),
+            Some(Entry::AllTagsIntermediate { path, .. }
+                | Entry::AllTagsTerminal { path, .. }
+                | Entry::Passthrough { path, .. }) => Some(path),
+            _ => None,
         }
-        error!("tried to lookup non existent inode: {inode:#x?}.");
-        panic!("tried to lookup non existent inode: {inode:#x?}.");
     }
 
     /// Get the tag value of an inode.
     ///
-    /// To call this function with an inode that does not exist is a
-    /// programming error, therefore we panic if it does not exist.
-    /// Additionally it is a programming error to call this function with an
-    /// inode type that is not a [`Entry::ValueDir`] therefore we also panic in
-    /// this case.
-    pub fn get_tag_value(&self, inode: u64) -> &str {
+    /// Returns None if the inode is not an [`Entry::ValueDir`].
+    pub fn get_tag_value(&self, inode: u64) -> Option<&str> {
         if let Some(Entry::ValueDir { value, .. }) = self.attrs.get(&inode) {
-            value
+            Some(value)
         } else {
-            error!("tried to lookup non existent inode: {inode:#x?}.");
-            panic!("tried to lookup non existent inode: {inode:#x?}.");
+            None
         }
     }
 
     /// Get the type of an inode.
     ///
-    /// To call this function with an inode that does not exist is a
-    /// programming error, therefore we panic if it does not exist.
-    pub fn get_type(&self, inode: u64) -> EntryType {
-        if let Some(entry) = self.attrs.get(&inode) {
-            match entry {
-                Entry::Root { .. } => EntryType::Root,
-                Entry::QueryDir { .. } => EntryType::QueryDir,
-                Entry::QueryResultDir { .. } => EntryType::QueryResultDir,
-                Entry::TagDir { .. } => EntryType::TagDir,
-                Entry::ValueDir { .. } => EntryType::ValueDir,
-                Entry::Link { .. } => EntryType::Link,
-                Entry::AllTagsDir { .. } => EntryType::AllTagsDir,
-                Entry::AllTagsIntermediate { .. }
-                    => EntryType::AllTagsIntermediate,
-                Entry::AllTagsTerminal { .. } => EntryType::AllTagsTerminal,
-            }
-        } else {
-            error!("tried to lookup non existent inode: {inode:#x?}.");
-            panic!("tried to lookup non existent inode: {inode:#x?}.");
-        }
+    /// Returns None if the inode does not exist.
+    pub fn get_type(&self, inode: u64) -> Option<EntryType> {
+        self.attrs.get(&inode).map(|entry| match entry {
+            Entry::Root { .. } => EntryType::Root,
+            Entry::QueryDir { .. } => EntryType::QueryDir,
+            Entry::QueryResultDir { .. } => EntryType::QueryResultDir,
+            Entry::TagDir { .. } => EntryType::TagDir,
+            Entry::ValueDir { .. } => EntryType::ValueDir,
+            Entry::Link { .. } => EntryType::Link,
+            Entry::AllTagsDir { .. } => EntryType::AllTagsDir,
+            Entry::AllTagsIntermediate { .. }
+                => EntryType::AllTagsIntermediate,
+            Entry::AllTagsTerminal { .. } => EntryType::AllTagsTerminal,
+            Entry::Passthrough { .. } => EntryType::Passthrough,
+            Entry::ControlDir { .. } => EntryType::ControlDir,
+            Entry::ControlFile { .. } => EntryType::ControlFile,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fuser::FUSE_ROOT_ID;
+
+    use super::{Entries, MountConfig};
+
+    #[test]
+    fn forget_test() {
+        let mut entries = Entries::new(&MountConfig::default());
+
+        let tag_inode = entries.get_or_create_tag_directory(FUSE_ROOT_ID,
+            "genre", 1);
+        let value_inode = entries.get_or_create_value_directory(tag_inode,
+            "crime", "crime");
+        let link_inode = entries.create_link(value_inode, "Heat (1995)", 1,
+            11);
+
+        // children the kernel does not refer to are evicted with their parent.
+        entries.lookup(tag_inode);
+        entries.lookup(link_inode);
+        entries.forget(tag_inode, 1);
+        assert_eq!(entries.try_get_inode(FUSE_ROOT_ID, "genre"), None);
+        assert!(!entries.attrs.contains_key(&value_inode));
+        assert!(entries.attrs.contains_key(&link_inode));
+
+        entries.forget(link_inode, 1);
+        assert_eq!(entries.attrs.len(), 1);
+
+        // the same entry is given the same inode when it is recreated.
+        assert_eq!(tag_inode, entries.get_or_create_tag_directory(
+            FUSE_ROOT_ID, "genre", 1));
+
+        // evicting a child means the parent has to be listed again.
+        let value_inode = entries.get_or_create_value_directory(tag_inode,
+            "crime", "crime");
+        entries.set_listed_version(tag_inode, 1);
+        entries.lookup(value_inode);
+        entries.forget(value_inode, 1);
+        assert!(!entries.is_listed_at(tag_inode, 1));
+
+        // unlisted children are pruned.
+        entries.get_or_create_value_directory(tag_inode, "crime", "crime");
+        entries.retain_children(tag_inode, &[]);
+        assert_eq!(entries.attrs.len(), 2);
     }
 }
@@ -1,21 +1,59 @@
-//! generates new inode without conflicts.
+//! generates stable inodes without conflicts.
+//!
+//! An inode is derived from the inode of the parent and a key that identifies
+//! the entry in the database (TagID, TagMappingID, value...). Therefore the
+//! same entry is given the same inode every time the filesystem is mounted,
+//! regardless of the order in which entries are created.
 
 use fuser::FUSE_ROOT_ID;
 
-#[derive(Debug)]
-pub(super) struct INodeGenerator {
-    last_value: u64
-}
+/// 64 bit FNV-1a offset basis.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// 64 bit FNV-1a prime.
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Returns the inode of the entry identified by key within parent_inode.
+///
+/// If the inode is already taken by another entry, the next free inode is
+/// used instead. Only entries that collide can change inode between mounts.
+pub(super) fn generate(parent_inode: u64, key: &str,
+                       is_taken: impl Fn(u64) -> bool) -> u64
+{
+    let bytes = parent_inode.to_le_bytes().into_iter()
+        .chain(key.bytes());
+    let mut inode = hash(bytes);
 
-impl INodeGenerator {
-    pub(crate) const fn new() -> Self {
-        Self {
-            last_value: FUSE_ROOT_ID,
-        }
+    while inode <= FUSE_ROOT_ID || is_taken(inode) {
+        inode = inode.wrapping_add(1);
     }
 
-    pub(crate) fn next(&mut self) -> u64 {
-        self.last_value += 1;
-        self.last_value
+    inode
+}
+
+/// Returns the 64 bit FNV-1a hash of bytes.
+///
+/// We use our own hash function rather than the standard library one,
+/// because the standard library does not guarantee that its hash is stable
+/// between releases.
+pub(super) fn hash(bytes: impl Iterator<Item=u8>) -> u64 {
+    bytes.fold(FNV_OFFSET_BASIS, |hash, byte|
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::generate;
+
+    #[test]
+    fn generate_test() {
+        let inode = generate(1, "tag:1", |_| false);
+
+        assert_eq!(inode, generate(1, "tag:1", |_| false));
+        assert_ne!(inode, generate(1, "tag:2", |_| false));
+        assert_ne!(inode, generate(2, "tag:1", |_| false));
+
+        let next = generate(1, "tag:1", |taken| taken == inode);
+        assert_eq!(next, inode.wrapping_add(1));
     }
 }
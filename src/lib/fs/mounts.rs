@@ -0,0 +1,109 @@
+//! finds and unmounts the active tagfs mounts.
+
+use std::io::{Error, ErrorKind};
+use std::process::Command;
+
+/// Name of the filesystem, shown as the source of a tagfs mount in the mount
+/// table.
+pub(super) const FS_NAME: &str = "tagfs";
+
+/// Returns the mount points of every active tagfs mount.
+///
+/// # Errors
+/// Returns an error if the mount table cannot be read.
+pub fn mounts() -> std::io::Result<Vec<String>> {
+    let table = std::fs::read_to_string("/proc/self/mounts")?;
+
+    let mount_points = table.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let source = fields.next()?;
+            let mount_point = fields.next()?;
+            let mount_type = fields.next()?;
+
+            // the type is fuse.tagfs when mounted with fusermount, otherwise
+            // the subtype is dropped.
+            let is_tagfs = source == FS_NAME
+                && (mount_type == "fuse" || mount_type == "fuse.tagfs");
+            is_tagfs.then(|| unescape(mount_point))
+        })
+        .collect();
+
+    Ok(mount_points)
+}
+
+/// Unmounts the tagfs mount at mnt_point the same way as `fusermount -u`.
+/// The process serving the mount exits once it has been unmounted.
+///
+/// # Errors
+/// Returns an error if mnt_point is not a tagfs mount or if it could not be
+/// unmounted, for example because it is busy.
+pub fn unmount(mnt_point: &str) -> std::io::Result<()> {
+    let mnt_point = mnt_point.trim_end_matches('/');
+
+    if !mounts()?.iter().any(|mount| mount == mnt_point) {
+        return Err(Error::new(ErrorKind::NotFound,
+            format!("\"{mnt_point}\" is not a tagfs mount.")));
+    }
+
+    // fusermount3 comes with libfuse 3, fusermount with libfuse 2.
+    for fusermount in ["fusermount3", "fusermount"] {
+        match Command::new(fusermount).arg("-u").arg(mnt_point).status() {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => {
+                return Err(Error::other(
+                    format!("{fusermount} exited with {status}.")));
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    // without fusermount we can still unmount if we are allowed to.
+    let path = std::ffi::CString::new(mnt_point)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::umount(path.as_ptr()) } == 0 {
+        Ok(())
+    } else {
+        Err(Error::last_os_error())
+    }
+}
+
+/// Reverses the octal escapes the mount table uses for whitespace and
+/// backslashes in mount points.
+fn unescape(field: &str) -> String {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        let code = tail.get(..3)
+            .and_then(|code| std::str::from_utf8(code).ok())
+            .and_then(|code| u8::from_str_radix(code, 8).ok());
+
+        match code {
+            Some(code) if byte == b'\\' => {
+                bytes.push(code);
+                rest = &tail[3..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unescape;
+
+    #[test]
+    fn unescape_test() {
+        assert_eq!(unescape("/mnt/tags"), "/mnt/tags");
+        assert_eq!(unescape("/mnt/my\\040tags"), "/mnt/my tags");
+        assert_eq!(unescape("/mnt/back\\134slash"), "/mnt/back\\slash");
+        assert_eq!(unescape("/mnt/not\\escaped"), "/mnt/not\\escaped");
+    }
+}
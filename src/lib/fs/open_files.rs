@@ -0,0 +1,107 @@
+//! keeps the contents of files that are open for writing until they are
+//! flushed, and the real files opened in passthrough mode.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::Arc;
+
+/// Buffered contents of an open file.
+#[derive(Debug)]
+pub(super) struct OpenFile {
+    /// inode of the file that was opened.
+    pub inode: u64,
+    pub contents: Vec<u8>,
+    /// whether the contents have changed since the last flush.
+    pub dirty: bool,
+    /// paths matching the query in the contents of an open query file, once
+    /// it has been read. Cleared whenever the contents change.
+    pub results: Option<Vec<u8>>,
+}
+
+impl OpenFile {
+    /// Write data into the buffer at offset, extending it if necessary.
+    pub(crate) fn write(&mut self, offset: usize, data: &[u8]) {
+        let end = offset + data.len();
+        if self.contents.len() < end {
+            self.contents.resize(end, 0);
+        }
+
+        self.contents[offset..end].copy_from_slice(data);
+        self.dirty = true;
+        self.results = None;
+    }
+
+    /// Truncate the buffer to size. A larger size leaves the buffer as it
+    /// is, rather than padding it with zeros.
+    pub(crate) fn truncate(&mut self, size: usize) {
+        self.contents.truncate(size);
+        self.dirty = true;
+        self.results = None;
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct OpenFiles {
+    /// the last file handle that was handed out.
+    last_fh: u64,
+
+    /// file handle -> OpenFile
+    files: HashMap<u64, OpenFile>,
+
+    /// file handle -> real file opened in passthrough mode, shared so that it
+    /// can be read without holding on to the open files.
+    real_files: HashMap<u64, Arc<File>>,
+}
+
+impl OpenFiles {
+    pub(crate) fn new() -> Self {
+        Self {
+            last_fh: 0,
+            files: HashMap::new(),
+            real_files: HashMap::new(),
+        }
+    }
+
+    /// Start buffering an open file with its initial contents and return its
+    /// file handle.
+    pub(crate) fn open(&mut self, inode: u64, contents: Vec<u8>) -> u64 {
+        self.last_fh += 1;
+        self.files.insert(self.last_fh, OpenFile {
+            inode, contents, dirty: false, results: None,
+        });
+        self.last_fh
+    }
+
+    /// Keep a real file open and return its file handle.
+    pub(crate) fn open_real(&mut self, file: File) -> u64 {
+        self.last_fh += 1;
+        self.real_files.insert(self.last_fh, Arc::new(file));
+        self.last_fh
+    }
+
+    pub(crate) fn get_real(&self, fh: u64) -> Option<Arc<File>> {
+        self.real_files.get(&fh).cloned()
+    }
+
+    pub(crate) fn get_mut(&mut self, fh: u64) -> Option<&mut OpenFile> {
+        self.files.get_mut(&fh)
+    }
+
+    /// Find an open file by inode. Used when the kernel does not tell us
+    /// which file handle a request is for.
+    pub(crate) fn find_mut(&mut self, inode: u64) -> Option<&mut OpenFile> {
+        self.files.values_mut().find(|file| file.inode == inode)
+    }
+
+    /// Returns the file handles of every open file.
+    pub(crate) fn handles(&self) -> Vec<u64> {
+        self.files.keys().chain(self.real_files.keys()).copied().collect()
+    }
+
+    /// Stop buffering a file and return what was buffered. Real files are
+    /// closed.
+    pub(crate) fn close(&mut self, fh: u64) -> Option<OpenFile> {
+        self.real_files.remove(&fh);
+        self.files.remove(&fh)
+    }
+}
@@ -0,0 +1,117 @@
+//! serves the real files and directories that links refer to, for mounts in
+//! passthrough mode.
+
+use std::fs::{File, Metadata};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType};
+
+use crate::path::{decode_path, encode};
+
+/// Returns the attributes of the real file at path, given the inode of the
+/// entry that refers to it. Symlinks are followed unless they are dangling,
+/// in which case the attributes of the symlink itself are returned.
+pub(super) fn attr(inode: u64, path: &str) -> std::io::Result<FileAttr> {
+    let path = decode_path(path);
+    let metadata = std::fs::metadata(&path)
+        .or_else(|_| std::fs::symlink_metadata(&path))?;
+
+    Ok(attr_from_metadata(inode, &metadata))
+}
+
+/// Converts the metadata of a real file to the attributes of inode.
+pub(super) fn attr_from_metadata(inode: u64, metadata: &Metadata)
+    -> FileAttr
+{
+    let mtime = system_time(metadata.mtime(), metadata.mtime_nsec());
+
+    FileAttr {
+        ino: inode,
+        size: metadata.size(),
+        blocks: metadata.blocks(),
+        atime: system_time(metadata.atime(), metadata.atime_nsec()),
+        mtime,
+        ctime: system_time(metadata.ctime(), metadata.ctime_nsec()),
+        crtime: metadata.created().unwrap_or(mtime),
+        kind: file_type(metadata),
+        perm: (metadata.mode() & 0o7777) as u16,
+        nlink: metadata.nlink() as u32,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: metadata.rdev() as u32,
+        flags: 0,
+        blksize: metadata.blksize() as u32,
+    }
+}
+
+/// Returns the names of the children of the real directory at path, sorted
+/// so that offsets into the listing are stable.
+pub(super) fn children(path: &str) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+
+    for child in std::fs::read_dir(decode_path(path))? {
+        let name = child?.file_name();
+        names.push(encode(&name).into_owned());
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+/// Reads up to size bytes from file at offset. Fewer bytes are only returned
+/// at the end of the file.
+pub(super) fn read(file: &File, offset: u64, size: usize)
+    -> std::io::Result<Vec<u8>>
+{
+    let mut buf = vec![0; size];
+    let mut len = 0;
+
+    while len < size {
+        match file.read_at(&mut buf[len..], offset + len as u64) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Returns the errno to reply with for an io error.
+pub(super) fn errno(e: &std::io::Error) -> libc::c_int {
+    e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+fn file_type(metadata: &Metadata) -> FileType {
+    let file_type = metadata.file_type();
+
+    if file_type.is_dir() {
+        FileType::Directory
+    } else if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_block_device() {
+        FileType::BlockDevice
+    } else if file_type.is_char_device() {
+        FileType::CharDevice
+    } else if file_type.is_fifo() {
+        FileType::NamedPipe
+    } else if file_type.is_socket() {
+        FileType::Socket
+    } else {
+        FileType::RegularFile
+    }
+}
+
+/// Converts a unix timestamp to a [`SystemTime`], including times before the
+/// epoch.
+fn system_time(secs: i64, nsecs: i64) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::new(secs as u64, nsecs as u32)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+            + Duration::from_nanos(nsecs as u64)
+    }
+}
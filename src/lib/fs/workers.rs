@@ -0,0 +1,101 @@
+//! runs FUSE requests on a pool of threads, so that a slow request does not
+//! hold up the others.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+
+use log::error;
+
+use crate::fs::lock;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Fixed number of threads that run jobs in the order they are queued.
+#[derive(Debug)]
+pub(super) struct Workers {
+    /// Queue of jobs, None once the workers are shutting down.
+    sender: Option<mpsc::Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    pub(super) fn new(count: usize) -> std::io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let threads = (0..count)
+            .map(|idx| {
+                let receiver = Arc::clone(&receiver);
+                std::thread::Builder::new()
+                    .name(format!("tagfs-worker-{idx}"))
+                    .spawn(move || Self::run(&receiver))
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(Self { sender: Some(sender), threads })
+    }
+
+    /// Queues job to run on the next free thread.
+    pub(super) fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        let sent = self.sender.as_ref()
+            .is_some_and(|sender| sender.send(Box::new(job)).is_ok());
+
+        if !sent {
+            error!("could not queue a request, the workers have stopped.");
+        }
+    }
+
+    /// Runs jobs until the queue is closed. A job that panics drops its reply,
+    /// which answers the request with an error, and the thread carries on.
+    fn run(receiver: &Mutex<mpsc::Receiver<Job>>) {
+        loop {
+            // the lock is released before the job runs so that other threads
+            // can take the next one.
+            let job = lock(receiver).recv();
+            let Ok(job) = job else { break };
+
+            if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                error!("a request panicked.");
+            }
+        }
+    }
+}
+
+impl Drop for Workers {
+    // waits for the queued jobs to finish.
+    fn drop(&mut self) {
+        self.sender = None;
+
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                error!("a worker thread panicked.");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::Workers;
+
+    #[test]
+    fn workers_test() {
+        let (sender, receiver) = mpsc::channel();
+        let workers = Workers::new(2).unwrap();
+
+        for idx in 0..8 {
+            let sender = sender.clone();
+            workers.execute(move || sender.send(idx).unwrap());
+        }
+
+        // dropping the workers waits for every queued job.
+        drop(workers);
+
+        let mut done = receiver.try_iter().collect::<Vec<_>>();
+        done.sort_unstable();
+        assert_eq!(done, (0..8).collect::<Vec<_>>());
+    }
+}
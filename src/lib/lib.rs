@@ -1,6 +1,7 @@
 //! Library crate that contains the main functionality for tagfs.
 pub mod db;
 pub mod fs;
+pub mod path;
 mod error;
 
 #[cfg(feature = "autotag")]
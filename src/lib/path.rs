@@ -0,0 +1,119 @@
+//! Module that losslessly encodes file names and paths that are not valid
+//! UTF-8 as strings, so that they can be stored in the database and shown in
+//! the filesystem like any other path.
+//!
+//! Valid UTF-8 is left as it is. Every byte that is not part of a valid UTF-8
+//! sequence is encoded as the character U+EF00 plus the byte, which is in the
+//! private use area U+EF80..=U+EFFF. Characters that are already in that
+//! range are encoded byte by byte as well, so that every encoded string
+//! decodes to exactly the bytes it was encoded from.
+//!
+//! Every path in the database is encoded, paths are only decoded to access
+//! the real files or to show them outside of tagfs.
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// First character of the range that bytes are encoded as.
+const BYTE_BASE: u32 = 0xEF00;
+
+/// Encode a file name or a path as a string.
+pub fn encode(name: &OsStr) -> Cow<'_, str> {
+    match name.to_str() {
+        Some(name) if !name.chars().any(is_encoded_byte) => {
+            Cow::Borrowed(name)
+        }
+        _ => Cow::Owned(encode_bytes(name.as_bytes())),
+    }
+}
+
+/// Decode a string created with [`encode`] back into the file name or path
+/// it was encoded from.
+pub fn decode(encoded: &str) -> Cow<'_, OsStr> {
+    if !encoded.chars().any(is_encoded_byte) {
+        return Cow::Borrowed(OsStr::new(encoded));
+    }
+
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut buf = [0; 4];
+
+    for c in encoded.chars() {
+        if is_encoded_byte(c) {
+            // the range only contains bytes 0x80 to 0xff.
+            bytes.push((c as u32 - BYTE_BASE) as u8);
+        } else {
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        }
+    }
+
+    Cow::Owned(OsString::from_vec(bytes))
+}
+
+/// Decode a path created with [`encode`] back into the real path it was
+/// encoded from.
+pub fn decode_path(encoded: &str) -> Cow<'_, Path> {
+    match decode(encoded) {
+        Cow::Borrowed(path) => Cow::Borrowed(Path::new(path)),
+        Cow::Owned(path) => Cow::Owned(PathBuf::from(path)),
+    }
+}
+
+fn encode_bytes(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    let mut buf = [0; 4];
+
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if is_encoded_byte(c) {
+                encoded.extend(c.encode_utf8(&mut buf).bytes()
+                    .map(encode_byte));
+            } else {
+                encoded.push(c);
+            }
+        }
+
+        encoded.extend(chunk.invalid().iter().copied().map(encode_byte));
+    }
+
+    encoded
+}
+
+fn encode_byte(byte: u8) -> char {
+    // bytes below 0x80 are always valid UTF-8, so they are never encoded.
+    char::from_u32(BYTE_BASE + u32::from(byte)).unwrap_or('\u{FFFD}')
+}
+
+fn is_encoded_byte(c: char) -> bool {
+    (BYTE_BASE + 0x80..=BYTE_BASE + 0xff).contains(&(c as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::{decode, encode};
+
+    #[test]
+    fn encode_test() {
+        let roundtrip = |bytes: &[u8]| {
+            let encoded = encode(OsStr::from_bytes(bytes)).into_owned();
+            assert_eq!(decode(&encoded).as_bytes(), bytes);
+            encoded
+        };
+
+        // valid UTF-8 is left as it is.
+        assert_eq!(roundtrip("/films/Amélie (2001)".as_bytes()),
+            "/films/Amélie (2001)");
+
+        // latin-1 is not valid UTF-8.
+        assert_eq!(roundtrip(b"/films/Am\xe9lie (2001)"),
+            "/films/Am\u{efe9}lie (2001)");
+
+        // characters in the encoded range are encoded byte by byte.
+        assert_eq!(roundtrip("/\u{efe9}".as_bytes()),
+            "/\u{efee}\u{efbf}\u{efa9}");
+    }
+}
@@ -1,9 +1,10 @@
 //! Entry point of tagfs where subcommands are implemented.
 
 mod cli;
+mod daemon;
 
 use std::{
-    ffi::OsString, io::{Write, Read}, str::FromStr
+    ffi::OsString, io::{Write, Read}, os::unix::ffi::OsStrExt, str::FromStr
 };
 
 use anyhow::{bail, Context, Result};
@@ -11,11 +12,12 @@ use clap::Parser;
 use log::{error, warn, trace};
 
 use libtagfs::db::{Database, TagValuePair};
+use libtagfs::path::{decode, encode};
 
 use cli::{
     Args, Command, EditCommand, MountCommand, PrefixCommand, QueryCommand,
     StoredQueriesCommand, StoredQueriesSubCommand, TagCommand, TagsCommand,
-    UntagCommand,
+    UnmountCommand, UntagCommand, XattrCommand, XattrSubCommand,
 };
 
 #[cfg(feature = "autotag")]
@@ -25,12 +27,19 @@ use cli::AutotagCommand;
 static DEFAULT_LOG_LEVEL: &str = "info";
 
 /// Initialise the global logger with the default log level or the level
-/// specified in the RUST_LOG environment variable.
+/// specified in the RUST_LOG environment variable. An empty RUST_LOG counts
+/// as not set, because it would only let errors through and leave the log
+/// file of a daemon without the messages it is read for.
 fn init_logging() {
     use env_logger::{Builder, Env};
 
     let env = Env::default().default_filter_or(DEFAULT_LOG_LEVEL);
-    Builder::from_env(env)
+    let mut builder = Builder::from_env(env);
+    if std::env::var_os("RUST_LOG").is_some_and(|filter| filter.is_empty()) {
+        builder.parse_filters(DEFAULT_LOG_LEVEL);
+    }
+
+    builder
         .format(|sink, rec| {
             writeln!(sink, "[{}] {}", rec.level(), rec.args())
         })
@@ -45,7 +54,8 @@ fn tag_main(command: TagCommand, mut db: Database) -> Result<()> {
         bail!("cannot tag a relative path.");
     }
 
-    let path = path.as_str().trim_end_matches('/');
+    let path = encode(path.as_os_str());
+    let path = path.trim_end_matches('/');
 
     for tag in &command.tags {
         db.tag(path, &tag.tag, tag.value.as_deref())?;
@@ -55,7 +65,8 @@ fn tag_main(command: TagCommand, mut db: Database) -> Result<()> {
 
 /// Untag subcommand entry point.
 fn untag_main(command: UntagCommand, mut db: Database) -> Result<()> {
-    let path = command.path.as_str().trim_end_matches('/');
+    let path = encode(command.path.as_os_str());
+    let path = path.trim_end_matches('/');
 
     match command.tag {
         Some(TagValuePair { tag, value }) =>
@@ -74,8 +85,11 @@ fn query_main(command: QueryCommand, mut db: Database) -> Result<()> {
         bail!("no paths found matching query \"{}\".", query);
     }
 
+    // paths are printed as they are on disk, even if they are not UTF-8.
+    let mut stdout = std::io::stdout().lock();
     for (path, _) in &paths {
-        println!("{path}");
+        stdout.write_all(decode(path).as_bytes())?;
+        stdout.write_all(b"\n")?;
     }
 
     Ok(())
@@ -112,23 +126,99 @@ fn tags_specific_path_main(path: &str, db: Database) -> Result<()> {
     Ok(())
 }
 
-/// Mount subcommand entry point.
-fn mount_main(command: MountCommand, db: Database) -> Result<()> {
+/// Mount subcommand entry point. The database is opened with open_db, after
+/// starting the daemon because a database connection cannot be used by a
+/// forked process.
+fn mount_main(mut command: MountCommand, open_db: impl FnOnce() -> Database)
+    -> Result<()>
+{
+    // the daemon runs in the root directory, so the mount point cannot be
+    // relative.
+    command.mount_point = command.mount_point.canonicalize_utf8()
+        .with_context(|| format!("could not find mount point \"{}\".",
+                                 command.mount_point))?;
+
+    let pid_file = if command.daemon {
+        let pid_file = match &command.pid_file {
+            Some(pid_file) => pid_file.clone(),
+            None => cli::mount_state_file(&command.mount_point, "pid")?,
+        };
+        let log_file = match &command.log_file {
+            Some(log_file) => log_file.clone(),
+            None => cli::mount_state_file(&command.mount_point, "log")?,
+        };
+
+        daemon::daemonize(&command.mount_point, &pid_file, &log_file)?;
+        Some(pid_file)
+    } else {
+        None
+    };
 
+    let db = open_db();
     if !db.all_paths_valid()? {
         warn!("The database contains invalid paths. Mounting anyway...");
     }
 
-    libtagfs::fs::mount(command.mount_point.as_str(), db)
+    let mut config = command.mount_config();
+    if let Some(name) = &command.root_stored_query {
+        let stored_query = db.stored_queries()?.into_iter()
+            .find(|stored_query| stored_query.name == *name)
+            .with_context(|| format!("there is no stored query \"{name}\"."))?;
+        config.root_query = Some(stored_query.query);
+    }
+
+    let res = libtagfs::fs::mount(command.mount_point.as_str(), db, config)
         .context("an unexpected fuse error occured. \
-                  Please check the log for more details.")?;
+                  Please check the log for more details.");
+
+    if let Some(pid_file) = pid_file {
+        let _ = std::fs::remove_file(pid_file);
+    }
+
+    res
+}
+
+/// Unmount subcommand entry point.
+fn unmount_main(command: UnmountCommand) -> Result<()> {
+    // the mount point cannot be resolved if the daemon died without
+    // unmounting it.
+    let mount_point = command.mount_point.canonicalize_utf8()
+        .unwrap_or(command.mount_point);
+
+    let pid_file = match command.pid_file {
+        Some(pid_file) => pid_file,
+        None => cli::mount_state_file(&mount_point, "pid")?,
+    };
+    let pid = daemon::read_pid_file(&pid_file);
+
+    libtagfs::fs::unmount(mount_point.as_str())
+        .with_context(|| format!("could not unmount \"{mount_point}\"."))?;
+
+    // the daemon saves any open tags files and closes the database before
+    // it exits.
+    if let Some(pid) = pid {
+        daemon::wait_for_exit(pid)?;
+    }
+
+    Ok(())
+}
+
+/// Mounts subcommand entry point.
+fn mounts_main() -> Result<()> {
+    let mounts = libtagfs::fs::mounts()
+        .context("could not read the mount table.")?;
+
+    for mount in mounts {
+        println!("{mount}");
+    }
 
     Ok(())
 }
 
 /// Prefix subcommand entry point.
 fn prefix_main(command: PrefixCommand, mut db: Database) -> Result<()> {
-    db.prefix_change(&command.old_prefix, &command.new_prefix)
+    db.prefix_change(&encode(&command.old_prefix),
+        &encode(&command.new_prefix))
 }
 
 /// Edit subcommand entry point
@@ -222,6 +312,27 @@ fn stored_queries_main(command: StoredQueriesSubCommand, mut db: Database)
     Ok(())
 }
 
+/// Xattr subcommand entry point
+fn xattr_main(command: XattrSubCommand, mut db: Database) -> Result<()> {
+    match command {
+        XattrSubCommand::Sync { path } => {
+            if path.as_ref().is_some_and(|path| path.is_relative()) {
+                bail!("cannot sync a relative path.");
+            }
+
+            let root = path.as_ref().map(|path| encode(path.as_os_str()));
+            db.sync_to_xattrs(root.as_deref())
+        }
+        XattrSubCommand::Rebuild { path } => {
+            if path.is_relative() {
+                bail!("cannot rebuild from a relative path.");
+            }
+
+            db.sync_from_xattrs(&encode(path.as_os_str()))
+        }
+    }
+}
+
 // TODO: ensure path exists and inform user if not.
 #[cfg(feature = "autotag")]
 /// Autotag subcommand entry point.
@@ -241,13 +352,7 @@ fn autotag_main(command: AutotagCommand, mut db: Database) -> Result<()> {
             .filter(|metadata| metadata.is_file()).is_some());
 
     for entry in entries {
-        let path = entry.path();
-        if let Some(path) = path.to_str() {
-            autotagger.autotag(path, &mut db)?;
-        } else {
-            warn!("ignoring path \"{}\" due to invalid UTF-8.",
-                  path.display());
-        }
+        autotagger.autotag(&encode(entry.path().as_os_str()), &mut db)?;
     }
 
     Ok(())
@@ -286,30 +391,35 @@ fn main() {
     let args = Args::parse();
 
     let db_path = unwrap_or_exit(args.db_path());
-    let db = unwrap_or_exit(
+    let db = || unwrap_or_exit(
         libtagfs::db::get_or_create_db(Some(db_path.as_str()))
             .context("could not find or create a database. \
                     Please check the log for more details."));
 
     let err = match args.command {
-        Command::Tag(tag_command) => tag_main(tag_command, db),
-        Command::Untag(untag_command) => untag_main(untag_command, db),
+        Command::Tag(tag_command) => tag_main(tag_command, db()),
+        Command::Untag(untag_command) => untag_main(untag_command, db()),
         Command::Mount(mount_command) => mount_main(mount_command, db),
+        Command::Unmount(unmount_command) => unmount_main(unmount_command),
+        Command::Mounts => mounts_main(),
         Command::Tags(TagsCommand { path: Some(path), .. } ) =>
-            tags_specific_path_main(path.as_str(), db),
+            tags_specific_path_main(&encode(path.as_os_str()), db()),
         Command::Tags(TagsCommand { path: None, .. } ) =>
-            tags_all_main(db),
-        Command::Query(query_command) => query_main(query_command, db),
-        Command::Prefix(prefix_command) => prefix_main(prefix_command, db),
-        Command::Edit(edit_command) => edit_main(edit_command, db),
+            tags_all_main(db()),
+        Command::Query(query_command) => query_main(query_command, db()),
+        Command::Prefix(prefix_command) => prefix_main(prefix_command, db()),
+        Command::Edit(edit_command) => edit_main(edit_command, db()),
 
         Command::StoredQueries(StoredQueriesCommand { command }) => {
             let command = command.unwrap_or(StoredQueriesSubCommand::List);
-            stored_queries_main(command, db)
+            stored_queries_main(command, db())
         }
 
+        Command::Xattr(XattrCommand { command }) => xattr_main(command, db()),
+
         #[cfg(feature = "autotag")]
-        Command::Autotag(autotag_command) => autotag_main(autotag_command, db),
+        Command::Autotag(autotag_command) =>
+            autotag_main(autotag_command, db()),
     };
 
     display_and_log_error(err);
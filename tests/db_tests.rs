@@ -1,6 +1,8 @@
+use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
 use anyhow::Result;
 
-use libtagfs::db::TagMapping;
+use libtagfs::db::{TagMapping, TagValuePair};
 
 #[test]
 fn db_runthrough() -> Result<()> {
@@ -23,6 +25,25 @@ fn db_runthrough() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn db_path_count() -> Result<()> {
+    let mut db = libtagfs::db::get_or_create_db(None)?;
+    assert_eq!(db.path_count()?, 0);
+
+    // paths are counted once however many tags they have.
+    db.tag("hello", "cool-tag", None)?;
+    db.tag("goodbye", "cool-tag", None)?;
+    db.tag("hello", "other-tag", None)?;
+    db.tag("hello", "valued-tag", Some("value"))?;
+    assert_eq!(db.path_count()?, 2);
+    assert_eq!(db.mapping_count()?, 4);
+
+    db.untag_all("goodbye")?;
+    assert_eq!(db.path_count()?, 1);
+
+    Ok(())
+}
+
 #[test]
 fn db_untag() -> Result<()> {
 
@@ -199,3 +220,356 @@ fn db_query() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn db_empty_tags() -> Result<()> {
+    let mut db = libtagfs::db::get_or_create_db(None)?;
+
+    db.create_empty_tag("genre")?;
+    assert!(db.create_empty_tag("genre").is_err());
+    assert_eq!(db.all_tags()?, &["genre"]);
+    assert!(db.values("genre")?.is_empty());
+
+    db.create_empty_value("genre", "western")?;
+    assert!(db.create_empty_value("genre", "western").is_err());
+    assert_eq!(db.values("genre")?, &["western"]);
+
+    // the placeholder is removed once the value is used.
+    db.tag("Unforgiven (1992)", "genre", Some("western"))?;
+    db.tag("Heat (1995)", "genre", Some("crime"))?;
+    assert_eq!(db.values("genre")?, &["western", "crime"]);
+
+    db.untag("Unforgiven (1992)", "genre", None)?;
+    assert_eq!(db.values("genre")?, &["crime"]);
+
+    db.remove_value("genre", "crime")?;
+    assert!(db.all_tags()?.is_empty());
+
+    // an empty tag can still be used without a value.
+    db.create_empty_tag("favourite")?;
+    db.tag("Heat (1995)", "favourite", None)?;
+    assert!(db.create_empty_value("favourite", "yes").is_err());
+
+    db.tag("Casino (1995)", "favourite", None)?;
+    db.remove_tag("favourite")?;
+    assert!(db.all_tags()?.is_empty());
+    assert!(db.tags("Heat (1995)")?.is_empty());
+    assert!(db.remove_tag("favourite").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn db_rename() -> Result<()> {
+    let mut db = libtagfs::db::get_or_create_db(None)?;
+
+    db.tag("Heat (1995)", "genre", Some("crime"))?;
+    db.tag("Casino (1995)", "genre", Some("crime"))?;
+    db.tag("Casino (1995)", "genre", Some("drama"))?;
+    db.tag("Casino (1995)", "favourite", None)?;
+
+    db.rename_tag("genre", "category")?;
+    assert!(db.rename_tag("category", "favourite").is_err());
+    assert_eq!(db.all_tags()?, &["category", "favourite"]);
+
+    db.rename_value("category", "crime", "heist")?;
+    assert!(db.rename_value("category", "heist", "drama").is_err());
+    assert_eq!(db.values("category")?, &["heist", "drama"]);
+
+    db.retag("Heat (1995)", "category", Some("heist"),
+        "category", Some("drama"))?;
+    assert_eq!(db.values("category")?, &["heist", "drama"]);
+    let paths: Vec<_> = db.paths_with_tag("category", Some("drama"))?
+        .into_iter().map(|(path, _)| path).collect();
+    assert_eq!(paths, &["Casino (1995)", "Heat (1995)"]);
+
+    // the untag is rolled back if the new tag cannot be applied.
+    assert!(db.retag("Casino (1995)", "category", Some("heist"),
+        "category", Some("drama")).is_err());
+    let paths: Vec<_> = db.paths_with_tag("category", Some("heist"))?
+        .into_iter().map(|(path, _)| path).collect();
+    assert_eq!(paths, &["Casino (1995)"]);
+
+    Ok(())
+}
+
+#[test]
+fn db_matching() -> Result<()> {
+    let mut db = libtagfs::db::get_or_create_db(None)?;
+
+    db.tag("Heat (1995)", "genre", Some("crime"))?;
+    db.tag("Heat (1995)", "year", Some("1995"))?;
+    db.tag("Casino (1995)", "genre", Some("crime"))?;
+    db.tag("Casino (1995)", "genre", Some("drama"))?;
+    db.tag("Casino (1995)", "year", Some("1995"))?;
+    db.tag("Casino (1995)", "favourite", None)?;
+    db.tag("Goodfellas (1990)", "genre", Some("crime"))?;
+    db.tag("Goodfellas (1990)", "year", Some("1990"))?;
+
+    let filter = [
+        "genre=crime".parse::<TagValuePair>()?,
+    ];
+    assert_eq!(db.values_matching("year", &filter)?, &["1995", "1990"]);
+    assert_eq!(db.tags_matching(&filter)?, &["genre", "year", "favourite"]);
+
+    let paths: Vec<_> = db.paths_with_tag_matching("year", Some("1995"),
+            &filter)?
+        .into_iter().map(|(path, _)| path).collect();
+    assert_eq!(paths, &["Heat (1995)", "Casino (1995)"]);
+
+    let filter = [
+        "year=1995".parse::<TagValuePair>()?,
+        "favourite".parse::<TagValuePair>()?,
+    ];
+    assert_eq!(db.values_matching("genre", &filter)?, &["crime", "drama"]);
+
+    let paths: Vec<_> = db.paths_with_tag_matching("genre", Some("drama"),
+            &filter)?
+        .into_iter().map(|(path, _)| path).collect();
+    assert_eq!(paths, &["Casino (1995)"]);
+
+    // values are matched exactly.
+    let filter = ["genre=crim".parse::<TagValuePair>()?];
+    assert!(db.tags_matching(&filter)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn db_replace_manual_tags() -> Result<()> {
+    use std::str::FromStr;
+    use libtagfs::db::TagValuePair;
+
+    let mut db = libtagfs::db::get_or_create_db(None)?;
+
+    let path = "Heat (1995)";
+    db.tag(path, "genre", Some("crime"))?;
+    db.tag(path, "favourite", None)?;
+
+    let tags = ["genre=thriller", "genre=crime", "genre=crime"]
+        .map(|tag| TagValuePair::from_str(tag).unwrap());
+    db.replace_manual_tags(path, &tags)?;
+
+    let tags: Vec<_> = db.tags(path)?.into_iter()
+        .map(|tag| (tag.tag.name, tag.value)).collect();
+    assert_eq!(tags, &[
+        (String::from("genre"), Some(String::from("thriller"))),
+        (String::from("genre"), Some(String::from("crime"))),
+    ]);
+
+    // nothing is changed if any of the tags cannot be applied.
+    let tags = ["year=1995", "genre=drama", "genre"]
+        .map(|tag| TagValuePair::from_str(tag).unwrap());
+    assert!(db.replace_manual_tags(path, &tags).is_err());
+    assert_eq!(db.tags(path)?.len(), 2);
+    assert_eq!(db.all_tags()?, &["genre"]);
+
+    Ok(())
+}
+
+#[test]
+fn db_xattr_sync() -> Result<()> {
+    let tmp_dir = mktemp::Temp::new_dir()?;
+    let root = tmp_dir.to_str().unwrap().to_owned();
+    let film = format!("{root}/Heat (1995)");
+    let moved_film = format!("{root}/films/Heat (1995)");
+    std::fs::create_dir_all(format!("{root}/films"))?;
+    std::fs::write(&film, "")?;
+
+    let mut db = libtagfs::db::get_or_create_db(None)?;
+    db.tag(&film, "genre", Some("crime"))?;
+    db.tag(&film, "genre", Some("thriller"))?;
+    db.tag(&film, "favourite", None)?;
+    db.sync_to_xattrs(None)?;
+
+    // the tags follow the file when it is moved outside of tagfs.
+    std::fs::rename(&film, &moved_film)?;
+    db.sync_from_xattrs(&root)?;
+
+    assert!(db.tags(&film)?.is_empty());
+    let tags: Vec<_> = db.tags(&moved_film)?.into_iter()
+        .map(|tag| (tag.tag.name, tag.value)).collect();
+    assert_eq!(tags, &[
+        (String::from("favourite"), None),
+        (String::from("genre"), Some(String::from("crime"))),
+        (String::from("genre"), Some(String::from("thriller"))),
+    ]);
+
+    // removed tags are removed from the file.
+    db.untag(&moved_film, "genre", None)?;
+    db.sync_to_xattrs(None)?;
+    db.sync_from_xattrs(&root)?;
+    let tags: Vec<_> = db.tags(&moved_film)?.into_iter()
+        .map(|tag| tag.tag.name).collect();
+    assert_eq!(tags, &["favourite"]);
+
+    // paths that cannot be read, such as dangling symlinks, are skipped.
+    std::os::unix::fs::symlink("/does/not/exist", format!("{root}/dangling"))?;
+    db.sync_from_xattrs(&root)?;
+    assert_eq!(db.tags(&moved_film)?.len(), 1);
+
+    // tagged dangling symlinks still exist, so their tags are kept.
+    let dangling = format!("{root}/dangling");
+    db.tag(&dangling, "broken", None)?;
+    db.sync_from_xattrs(&root)?;
+    assert_eq!(db.tags(&dangling)?.len(), 1);
+
+    // paths under a directory that cannot be searched might still exist, so
+    // their tags are kept.
+    let locked_dir = format!("{root}/locked");
+    let locked_film = format!("{locked_dir}/Ronin (1998)");
+    std::fs::create_dir(&locked_dir)?;
+    std::fs::write(&locked_film, "")?;
+    db.tag(&locked_film, "genre", Some("thriller"))?;
+    std::fs::set_permissions(&locked_dir, Permissions::from_mode(0o000))?;
+    let res = db.sync_from_xattrs(&root);
+    std::fs::set_permissions(&locked_dir, Permissions::from_mode(0o755))?;
+    res?;
+    assert_eq!(db.tags(&locked_film)?.len(), 1);
+
+    // paths without tags keep their attributes unless they are under the
+    // synced root.
+    db.untag(&moved_film, "favourite", None)?;
+    db.sync_to_xattrs(None)?;
+    db.sync_from_xattrs(&root)?;
+    assert_eq!(db.tags(&moved_film)?.len(), 1);
+
+    db.untag(&moved_film, "favourite", None)?;
+    db.sync_to_xattrs(Some(&root))?;
+    db.sync_from_xattrs(&root)?;
+    assert!(db.tags(&moved_film)?.is_empty());
+
+    // a missing root is refused rather than removing every path under it.
+    let missing = format!("{root}/missing");
+    db.tag(&format!("{missing}/file"), "favourite", None)?;
+    assert!(db.sync_from_xattrs(&missing).is_err());
+    assert!(db.sync_to_xattrs(Some(&missing)).is_err());
+    assert_eq!(db.tags(&format!("{missing}/file"))?.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn db_reopen() -> Result<()> {
+    let tmp_db = mktemp::Temp::new_file()?;
+    let db_path = tmp_db.as_os_str().to_str().unwrap();
+    let mut db = libtagfs::db::get_or_create_db(Some(db_path))?;
+
+    db.enable_wal()?;
+    let mut reader = db.reopen()?;
+    let version = reader.version()?;
+
+    // changes are seen by the other connection once they are committed, and
+    // change its version even though it did not write.
+    db.tag("/my/path", "cool-tag", None)?;
+    assert_eq!(reader.all_tags()?, &["cool-tag"]);
+    assert_ne!(reader.version()?, version);
+
+    let paths: Vec<_> = reader.paths_with_tag("cool-tag", None)?
+        .into_iter().map(|(path, _)| path).collect();
+    assert_eq!(paths, &["/my/path"]);
+
+    // an in-memory database only exists for its own connection.
+    assert!(libtagfs::db::get_or_create_db(None)?.reopen().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn db_stats() -> Result<()> {
+    let mut db = libtagfs::db::get_or_create_db(None)?;
+
+    db.tag("/", "favourite", None)?;
+    db.tag("/does/not/exist", "favourite", None)?;
+    db.tag("/does/not/exist", "genre", Some("crime"))?;
+    db.create_empty_tag("empty")?;
+
+    assert_eq!(db.tag_count()?, 3);
+    assert_eq!(db.path_count()?, 2);
+    assert_eq!(db.mapping_count()?, 3);
+
+    assert_eq!(db.invalid_paths()?, &["/does/not/exist"]);
+    assert!(!db.all_paths_valid()?);
+
+    Ok(())
+}
+
+#[test]
+fn db_non_utf8_paths() -> Result<()> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    use libtagfs::path::{decode, encode};
+
+    let mut db = libtagfs::db::get_or_create_db(None)?;
+
+    // latin-1 names are stored without losing the bytes that are not UTF-8.
+    let path = OsStr::from_bytes(b"/archive/caf\xe9");
+    db.tag(&encode(path), "archive", None)?;
+
+    let paths: Vec<_> = db.paths_with_tag("archive", None)?
+        .into_iter().map(|(path, _)| decode(&path).into_owned()).collect();
+    assert_eq!(paths, &[path]);
+
+    let invalid_paths = db.invalid_paths()?;
+    assert_eq!(decode(&invalid_paths[0]), path);
+
+    db.untag_all(&encode(path))?;
+    assert_eq!(db.path_count()?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn db_root_query() -> Result<()> {
+    let tmp_db = mktemp::Temp::new_file()?;
+    let db_path = tmp_db.as_os_str().to_str().unwrap();
+    let mut db = libtagfs::db::get_or_create_db(Some(db_path))?;
+
+    db.tag("/films/Heat (1995)", "type", Some("film"))?;
+    db.tag("/films/Heat (1995)", "genre", Some("crime"))?;
+    db.tag("/films/Alien (1979)", "type", Some("film"))?;
+    db.tag("/films/Alien (1979)", "genre", Some("horror"))?;
+    db.tag("/music/Heat", "type", Some("album"))?;
+    db.tag("/music/Heat", "rating", Some("5"))?;
+    db.create_empty_tag("empty")?;
+
+    assert!(db.set_root_query("type==film and").is_err());
+    db.set_root_query("type==film and not genre==horror")?;
+
+    let paths = |paths: Vec<(String, u64)>| paths.into_iter()
+        .map(|(path, _)| path).collect::<Vec<_>>();
+
+    // placeholders are kept, so that empty tags can still be created.
+    assert_eq!(db.all_tags()?, &["type", "genre", "empty"]);
+    assert_eq!(db.values("type")?, &["film"]);
+    assert_eq!(db.values("genre")?, &["crime"]);
+    assert_eq!(paths(db.paths_with_tag("type", None)?),
+        &["/films/Heat (1995)"]);
+    assert_eq!(db.paths_with_prefix("/")?, &["/films/Heat (1995)"]);
+    assert_eq!(paths(db.query("genre", false)?), &["/films/Heat (1995)"]);
+
+    let filter = ["type=film".parse::<TagValuePair>()?];
+    assert_eq!(db.tags_matching(&filter)?, &["type", "genre"]);
+
+    // the counts and the other listings only include the same paths.
+    db.create_stored_query("crime", "genre==crime")?;
+    db.create_stored_query("horror", "genre==horror")?;
+    assert_eq!(db.path_count()?, 1);
+    assert_eq!(db.tag_count()?, 3);
+    assert_eq!(db.mapping_count()?, 2);
+    assert_eq!(db.invalid_paths()?, &["/films/Heat (1995)"]);
+    let stored_queries: Vec<_> = db.stored_queries()?.into_iter()
+        .map(|stored_query| stored_query.name).collect();
+    assert_eq!(stored_queries, &["crime"]);
+
+    // tagging a path that would not match the root query changes nothing.
+    assert!(!db.tag_in_root("/films/Alien (1979)", "favourite", None)?);
+    assert_eq!(db.tags("/films/Alien (1979)")?.len(), 2);
+    assert!(db.tag_in_root("/films/Heat (1995)", "favourite", None)?);
+    assert_eq!(db.tags("/films/Heat (1995)")?.len(), 3);
+
+    // reopened connections keep the root query.
+    let mut reader = db.reopen()?;
+    assert_eq!(reader.values("type")?, &["film"]);
+
+    Ok(())
+}
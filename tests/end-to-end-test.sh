@@ -5,6 +5,7 @@ create_files() {
     tmp_db="$(mktemp || exit 1)"
     tmp_tree="$(mktemp -d || exit 1)"
     tmp_mount_point="$(mktemp -d || exit 1)"
+    tmp_state="$(mktemp -d || exit 1)"
 
     mkdir -p "${tmp_tree}/film" || exit 1
     mkdir -p "${tmp_tree}/film/Before Sunrise (1995)" || exit 1
@@ -18,21 +19,19 @@ tagfs() {
     target/debug/tagfs --database "${tmp_db}" "$@"
 }
 
+# RUST_LOG is emptied to check that the daemon logs at the default level.
 tagfs_mount() {
-    # cannot use the helper function to call mount because it obscures the PID
-    # making it difficult to kill the mount.
-    target/debug/tagfs --database "${tmp_db}" mount "${tmp_mount_point}" > /dev/null 2>&1 &
-    pid="$!"
-    disown
-
-    printf "%s\n" "${pid}"
+    RUST_LOG= tagfs mount --daemon \
+        --pid-file "${tmp_state}/tagfs.pid" \
+        --log-file "${tmp_state}/tagfs.log" \
+        "${tmp_mount_point}" || exit 1
 }
 
-# $1 is pid.
 cleanup() {
-    kill -TERM "${pid}"
+    tagfs unmount --pid-file "${tmp_state}/tagfs.pid" "${tmp_mount_point}"
 
     rm -r "${tmp_tree}"
+    rm -r "${tmp_state}"
     rmdir "${tmp_mount_point}"
     rm "${tmp_db}"
 }
@@ -55,7 +54,12 @@ assert_not_exists() {
 
 create_files
 
-pid="$(tagfs_mount)"
+tagfs_mount
+
+if ! grep -q 'Mounting filesystem' "${tmp_state}/tagfs.log"; then
+    printf "%s: fail: the daemon did not log that it mounted.\n" "${LINENO}" 1>&2
+    exit 1
+fi
 
 tagfs tag "${tmp_tree}/film/Before Sunrise (1995)" 'genre=romance'
 tagfs tag "${tmp_tree}/film/Before Sunset (2004)" 'genre=romance'
@@ -72,4 +76,4 @@ if [ "$(tagfs query --case-sensitive 'genre==ROMaNce' 2> /dev/null)" ]; then
     exit 1
 fi
 
-cleanup "${pid}"
+cleanup
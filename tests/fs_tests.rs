@@ -1,17 +1,24 @@
+//! Tests that mount the filesystem. They need FUSE and fusermount, so they
+//! are ignored by default. Run them with
+//! `cargo test --test fs_tests -- --ignored`.
+
 use std::{
-    ffi::OsString,
+    ffi::{CString, OsStr, OsString},
+    io::{Read, Seek, SeekFrom, Write},
+    os::{fd::IntoRawFd, unix::ffi::OsStrExt, unix::fs::MetadataExt},
     path::Path,
+    sync::mpsc,
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
-use once_cell::sync::OnceCell;
-
-static MOUNT_PATH: OnceCell<String> = OnceCell::new();
-static DB_PATH: OnceCell<String> = OnceCell::new();
+use anyhow::{bail, Result};
+use libtagfs::db::Database;
+use libtagfs::fs::{DuplicateNames, MountConfig};
+use libtagfs::path::encode;
 
 macro_rules! assert_symlink {
-    ($path:expr) => {
-        let path = format!("{}{}", MOUNT_PATH.get().unwrap(), $path);
+    ($mount:expr, $path:expr) => {
+        let path = format!("{}{}", $mount, $path);
         if let Ok(meta) = std::fs::symlink_metadata(&path) {
             assert!(meta.is_symlink(), "path {:?} is not a symlink.", $path);
         } else {
@@ -21,10 +28,10 @@ macro_rules! assert_symlink {
 }
 
 macro_rules! assert_symlink_target {
-    ($path:expr, $target:expr) => {
-        assert_symlink!($path);
+    ($mount:expr, $path:expr, $target:expr) => {
+        assert_symlink!($mount, $path);
 
-        let path = format!("{}{}", MOUNT_PATH.get().unwrap(), $path);
+        let path = format!("{}{}", $mount, $path);
 
         if let Ok(target) = std::fs::read_link(&path) {
             let target_path = Path::new(&$target);
@@ -37,8 +44,8 @@ macro_rules! assert_symlink_target {
 }
 
 macro_rules! assert_file {
-    ($path:expr) => {
-        let path = format!("{}{}", MOUNT_PATH.get().unwrap(), $path);
+    ($mount:expr, $path:expr) => {
+        let path = format!("{}{}", $mount, $path);
         let meta = std::fs::metadata(&path);
         assert!(meta.is_ok(), "path {:?} does not exist.", $path);
 
@@ -48,9 +55,9 @@ macro_rules! assert_file {
 }
 
 macro_rules! assert_file_contents {
-    ($path:expr, $contents:expr) => {
-        assert_file!($path);
-        let path = format!("{}{}", MOUNT_PATH.get().unwrap(), $path);
+    ($mount:expr, $path:expr, $contents:expr) => {
+        assert_file!($mount, $path);
+        let path = format!("{}{}", $mount, $path);
 
         // unwrap is fine because we assert file exists.
         let contents = std::fs::read_to_string(&path).unwrap();
@@ -59,8 +66,8 @@ macro_rules! assert_file_contents {
 }
 
 macro_rules! assert_dir_children {
-    ($path:expr, $children:expr) => {
-        let path = format!("{}{}", MOUNT_PATH.get().unwrap(), $path);
+    ($mount:expr, $path:expr, $children:expr) => {
+        let path = format!("{}{}", $mount, $path);
 
         let Ok(children) = std::fs::read_dir(&path) else {
             panic!("path \"{}\" does not exist or is not a directory.", $path);
@@ -76,38 +83,73 @@ macro_rules! assert_dir_children {
     }
 }
 
-#[test]
-fn fs_runthrough() -> Result<()> {
+/// A filesystem mounted in a temporary directory for a single test, with a
+/// database of its own.
+struct TestMount {
+    /// Absolute path of the mount point.
+    path: String,
+    /// Another connection to the database of the mount, to make changes
+    /// behind its back.
+    db: Database,
+    mount_dir: Option<mktemp::Temp>,
+    _db_file: mktemp::Temp,
+}
 
-    // the reason the temp directory is not cleaned up on drop is because the
-    // filesystem is still mounted when the destructor code runs, therefore it
-    // cannot be removed. This is not a huge issue, so I will leave it for now.
-    let tmp_mount = mktemp::Temp::new_dir()?;
-    MOUNT_PATH.set(tmp_mount.as_os_str().to_str().unwrap().to_owned())
-        .unwrap();
+impl Drop for TestMount {
+    fn drop(&mut self) {
+        // the temporary directory cannot be removed while it is still
+        // mounted, and removing it would remove the tags behind the mount.
+        if libtagfs::fs::unmount(&self.path).is_err() {
+            if let Some(mount_dir) = self.mount_dir.take() {
+                mount_dir.release();
+            }
+        }
+    }
+}
 
-    let tmp_db = mktemp::Temp::new_file()?;
-    DB_PATH.set(tmp_db.as_os_str().to_str().unwrap().to_owned())
-        .unwrap();
+/// The options most tests mount with.
+fn config() -> MountConfig {
+    MountConfig { max_depth: 2, target_attrs: true, ..MountConfig::default() }
+}
 
-    // mount the filesystem in a background thread.
-    std::thread::spawn(move || {
-        let db_path = &DB_PATH.get().unwrap();
-        let db = libtagfs::db::get_or_create_db(Some(db_path))?;
+/// Mounts an empty database with config in a background thread and waits
+/// until the mount is ready.
+fn mount(config: MountConfig) -> Result<TestMount> {
+    let mount_dir = mktemp::Temp::new_dir()?;
+    let path = std::fs::canonicalize(&mount_dir)?.to_str().unwrap()
+        .to_owned();
 
-        let mount_path = &MOUNT_PATH.get().unwrap();
-        libtagfs::fs::mount(mount_path, db)?;
+    let db_file = mktemp::Temp::new_file()?;
+    let db_path = db_file.as_os_str().to_str().unwrap().to_owned();
+    let db = libtagfs::db::get_or_create_db(Some(&db_path))?;
 
-        Ok::<(), anyhow::Error>(())
+    let (sender, receiver) = mpsc::channel();
+    let mount_path = path.clone();
+    std::thread::spawn(move || {
+        let res = libtagfs::db::get_or_create_db(Some(&db_path))
+            .and_then(|db| Ok(libtagfs::fs::mount(&mount_path, db, config)?));
+        if let Err(e) = res {
+            let _ = sender.send(e);
+        }
     });
 
-    // sleep to wait for fs to mount not exactly great but seems to work.
-    std::thread::sleep(std::time::Duration::from_millis(1000));
+    let start = Instant::now();
+    while !libtagfs::fs::mounts()?.contains(&path) {
+        if let Ok(e) = receiver.try_recv() {
+            return Err(e.context("could not mount the filesystem."));
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            bail!("timed out waiting for the filesystem to mount.");
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
 
-    let db_path = &DB_PATH.get().unwrap();
-    let mut db = libtagfs::db::get_or_create_db(Some(db_path))?;
+    Ok(TestMount { path, db, mount_dir: Some(mount_dir), _db_file: db_file })
+}
 
-    db.tag("/my/very/cool/path", "hello", None)?;
+/// Tags the paths most tests list. Each type directory has paths with the
+/// same name, and one path is both awesome and cool.
+fn tag_paths(db: &mut Database) -> Result<()> {
     db.tag("/my/very/cool/path", "type", Some("awesome"))?;
     db.tag("/my/other/very/cool/file", "type", Some("cool"))?;
     db.tag("/my/other/super/cool/path", "type", Some("awesome"))?;
@@ -115,44 +157,779 @@ fn fs_runthrough() -> Result<()> {
     db.tag("/my/other/super/cool/file", "type", Some("awesome"))?;
     db.tag("/my/other/super/cool/file", "type", Some("cool"))?;
 
-    assert_dir_children!("/", &["?", "hello", "tags", "type"]);
+    Ok(())
+}
+
+/// Returns the extended attribute of a path in the mount, or None if it does
+/// not exist.
+fn getxattr(mount: &str, path: &str, name: &str) -> Option<Vec<u8>> {
+    let path = CString::new(format!("{mount}{path}")).unwrap();
+    let name = CString::new(name).unwrap();
 
-    assert_dir_children!("/hello/", &["path"]);
-    assert_symlink_target!("/hello/path", "/my/very/cool/path");
+    let mut buf = vec![0u8; 1024];
+    let n = unsafe {
+        libc::getxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr().cast(),
+            buf.len())
+    };
 
-    assert_dir_children!("/type", &["awesome", "cool"]);
-    assert_dir_children!("/type/awesome/", &["file", "path.0", "path.1"]);
+    (n >= 0).then(|| { buf.truncate(n as usize); buf })
+}
 
-    db.untag("/my/very/cool/path", "hello", None)?;
-    assert_dir_children!("/", &["?", "tags", "type"]);
+/// Returns the names of the extended attributes of a path in the mount
+/// without following symlinks.
+fn listxattr(mount: &str, path: &str) -> Vec<String> {
+    let path = CString::new(format!("{mount}{path}")).unwrap();
+
+    let mut buf = vec![0u8; 1024];
+    let n = unsafe {
+        libc::llistxattr(path.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    };
+    assert!(n >= 0, "could not list extended attributes of {path:?}.");
+    buf.truncate(n as usize);
+
+    buf.split(|&c| c == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect()
+}
+
+/// Sets the extended attribute of a path in the mount.
+fn setxattr(mount: &str, path: &str, name: &str, value: &[u8]) -> bool {
+    let path = CString::new(format!("{mount}{path}")).unwrap();
+    let name = CString::new(name).unwrap();
+
+    let n = unsafe {
+        libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(),
+            value.len(), 0)
+    };
+    n == 0
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_tag_directories() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    db.tag("/my/very/cool/path", "hello", None)?;
+    tag_paths(db)?;
+
+    assert_dir_children!(mnt, "/", &["?", "hello", "tags", "type"]);
 
+    assert_dir_children!(mnt, "/hello/", &["path", "type"]);
+    assert_symlink_target!(mnt, "/hello/path", "/my/very/cool/path");
+
+    assert_dir_children!(mnt, "/type", &["awesome", "cool"]);
     assert_dir_children!(
-        "/?/type=awesome or type=cool/",
-        &["file.0", "file.1", "path.0", "path.1", "path.2"]
+        mnt, "/type/awesome/",
+        &["file", "hello", "path.0", "path.1"]
     );
 
-    assert_dir_children!("/?/type=awesome and type=cool/", &["file"]);
+    // tags are not nested where they would have the same name as a link.
+    db.tag("/my/very/cool/path", "path.0", None)?;
+    assert_dir_children!(
+        mnt, "/type/awesome/",
+        &["file", "hello", "path.0", "path.1"]
+    );
+    db.untag("/my/very/cool/path", "path.0", None)?;
 
-    assert_file_contents!(
-        "/tags/my/other/super/cool/file.tags",
-        "type=awesome\ntype=cool\n"
+    // tag directories are nested to narrow down the paths further.
+    assert_dir_children!(mnt, "/hello/type", &["awesome"]);
+    assert_dir_children!(mnt, "/hello/type/awesome", &["path"]);
+    assert_dir_children!(mnt, "/type/awesome/hello", &["path"]);
+    assert_symlink_target!(mnt, "/type/awesome/hello/path",
+        "/my/very/cool/path");
+
+    // nested directories are read only.
+    assert!(std::fs::remove_file(
+        format!("{mnt}/hello/type/awesome/path")).is_err());
+    assert!(std::fs::create_dir(
+        format!("{mnt}/hello/type/great")).is_err());
+
+    db.untag("/my/very/cool/path", "hello", None)?;
+    assert_dir_children!(mnt, "/", &["?", "tags", "type"]);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_queries() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+
+    assert_dir_children!(
+        mnt, "/?/type=awesome or type=cool/",
+        &["file.0", "file.1", "path.0", "path.1", "path.2"]
     );
 
+    assert_dir_children!(mnt, "/?/type=awesome and type=cool/", &["file"]);
+
     db.tag("/some/path/", "mytag", Some("a value with a / in it"))?;
-    assert_symlink_target!("/mytag/a value with a _ in it/path", "/some/path");
-    assert_dir_children!("/?/mytag", &["path"]);
+    assert_symlink_target!(mnt, "/mytag/a value with a _ in it/path",
+        "/some/path");
+    assert_dir_children!(mnt, "/?/mytag", &["path"]);
+
+    // ad-hoc queries are not listed.
+    assert_dir_children!(mnt, "/?", &[] as &[&str; 0]);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_stored_queries() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
 
-    assert_dir_children!("/?", &[] as &[&str; 0]);
+    tag_paths(db)?;
+    db.tag("/some/path/", "mytag", Some("a value with a / in it"))?;
 
     db.create_stored_query("my-query", "type=cool")?;
-    assert_dir_children!("/?", &["my-query @ [type=cool]"]);
+    assert_dir_children!(mnt, "/?", &["my-query @ [type=cool]"]);
     assert_dir_children!(
-        "/?/my-query @ [type=cool]/",
+        mnt, "/?/my-query @ [type=cool]/",
         &["file.0", "file.1", "path"]
     );
 
+    // stored queries are directories, which can also be found by the name of
+    // the query alone.
+    let query_dir = format!("{mnt}/?");
+    for entry in std::fs::read_dir(&query_dir)? {
+        assert!(entry?.file_type()?.is_dir());
+    }
+    assert_dir_children!(mnt, "/?/my-query", &["file.0", "file.1", "path"]);
+
+    // a / in a stored query is escaped in its name.
+    db.create_stored_query("slash", "mytag=/")?;
+    assert_dir_children!(mnt, "/?",
+        &["my-query @ [type=cool]", "slash @ [mytag=%2F]"]);
+    assert_dir_children!(mnt, "/?/slash @ [mytag=%2F]", &["path"]);
+
     db.delete_stored_query("my-query")?;
-    assert_dir_children!("/?", &[] as &[&str; 0]);
+    db.delete_stored_query("slash")?;
+    assert_dir_children!(mnt, "/?", &[] as &[&str; 0]);
+
+    // stored queries can also be created and deleted through the mount.
+    let created = format!("{query_dir}/cool=type==cool");
+    std::fs::create_dir(&created)?;
+    let created_inode = std::fs::metadata(&created)?.ino();
+    std::fs::create_dir(format!("{query_dir}/awesome @ [type==awesome]"))?;
+    assert_dir_children!(mnt, "/?",
+        &["awesome @ [type==awesome]", "cool @ [type==cool]"]);
+    assert_dir_children!(mnt, "/?/cool @ [type==cool]",
+        &["file.0", "file.1", "path"]);
+    assert_eq!(db.stored_queries()?[0].query, "type==cool");
+
+    // the name a stored query was created with can still be looked up once
+    // the kernel has forgotten it, and is the directory mkdir created.
+    std::thread::sleep(Duration::from_millis(1100));
+    assert!(Path::new(&created).is_dir());
+    assert_eq!(std::fs::metadata(&created)?.ino(), created_inode);
+    assert!(!Path::new(&format!("{query_dir}/cool=type==great")).exists());
+
+    // names are unique.
+    let err = std::fs::create_dir(format!("{query_dir}/cool=type==great"))
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+    assert!(std::fs::create_dir(format!("{query_dir}/cool @ [type]"))
+        .is_err());
+    assert!(std::fs::create_dir(format!("{query_dir}/bad=type==cool and"))
+        .is_err());
+    assert!(std::fs::remove_dir(format!("{query_dir}/type==cool")).is_err());
+
+    std::fs::remove_dir(format!("{query_dir}/cool @ [type==cool]"))?;
+    std::fs::remove_dir(format!("{query_dir}/awesome @ [type==awesome]"))?;
+    assert_dir_children!(mnt, "/?", &[] as &[&str; 0]);
+    assert!(db.stored_queries()?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_links() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+
+    // deleting a link removes the tag value pair from the linked path.
+    std::fs::remove_file(format!("{mnt}/type/awesome/file"))?;
+    assert_dir_children!(mnt, "/type/awesome/", &["path.0", "path.1"]);
+    assert_file_contents!(
+        mnt, "/tags/my/other/super/cool/file.tags",
+        "type=cool\n"
+    );
+
+    assert!(std::fs::remove_file(
+        format!("{mnt}/type/awesome/file")).is_err());
+
+    // creating a link tags the target with the tag value pair of the
+    // directory.
+    std::os::unix::fs::symlink("/my/other/very/cool/file",
+        format!("{mnt}/type/awesome/file"))?;
+    assert_dir_children!(mnt, "/type/awesome/", &["file", "path.0", "path.1"]);
+    assert_symlink_target!(mnt, "/type/awesome/file",
+        "/my/other/very/cool/file");
+    assert_file_contents!(
+        mnt, "/tags/my/other/very/cool/file.tags",
+        "type=cool\ntype=awesome\n"
+    );
+
+    // links are shown under the name of their target, so any other name is
+    // rejected without tagging the target.
+    assert!(std::os::unix::fs::symlink("/films/Tombstone (1993)",
+        format!("{mnt}/type/awesome/Tombstone")).is_err());
+    assert!(db.tags("/films/Tombstone (1993)")?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_empty_tags() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+    db.tag("/some/path/", "mytag", Some("a value with a / in it"))?;
+
+    // directories create empty tags and values and removing them removes the
+    // tag or value from every path.
+    std::fs::create_dir(format!("{mnt}/genre"))?;
+    assert_dir_children!(mnt, "/", &["?", "genre", "mytag", "tags", "type"]);
+    std::fs::create_dir(format!("{mnt}/genre/western"))?;
+    assert_dir_children!(mnt, "/genre", &["western"]);
+    assert_dir_children!(mnt, "/genre/western", &[] as &[&str; 0]);
+
+    std::os::unix::fs::symlink("/films/Unforgiven (1992)",
+        format!("{mnt}/genre/western/Unforgiven (1992)"))?;
+    assert_dir_children!(mnt, "/genre/western", &["Unforgiven (1992)"]);
+
+    std::fs::remove_dir(format!("{mnt}/genre/western"))?;
+    assert!(db.tags("/films/Unforgiven (1992)")?.is_empty());
+    assert_dir_children!(mnt, "/", &["?", "mytag", "tags", "type"]);
+
+    std::fs::remove_dir(format!("{mnt}/mytag"))?;
+    assert!(db.tags("/some/path")?.is_empty());
+    assert_dir_children!(mnt, "/", &["?", "tags", "type"]);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_rename() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+
+    // renaming directories renames tags and values.
+    std::fs::rename(format!("{mnt}/type/awesome"),
+        format!("{mnt}/type/great"))?;
+    assert_dir_children!(mnt, "/type", &["cool", "great"]);
+    assert_dir_children!(mnt, "/type/great", &["file", "path.0", "path.1"]);
+
+    std::fs::rename(format!("{mnt}/type"), format!("{mnt}/kind"))?;
+    assert_dir_children!(mnt, "/", &["?", "kind", "tags"]);
+
+    // moving a link retags its target, unless the target already has the
+    // new tag value pair.
+    assert!(std::fs::rename(format!("{mnt}/kind/great/file"),
+        format!("{mnt}/kind/cool/moved")).is_err());
+    assert_dir_children!(mnt, "/kind/great", &["file", "path.0", "path.1"]);
+
+    // the link keeps the name it is shown under in the new directory.
+    assert!(std::fs::rename(format!("{mnt}/kind/great/path.0"),
+        format!("{mnt}/kind/cool/moved")).is_err());
+    assert_dir_children!(mnt, "/kind/great", &["file", "path.0", "path.1"]);
+
+    std::fs::rename(format!("{mnt}/kind/great/path.0"),
+        format!("{mnt}/kind/cool/path.1"))?;
+    assert_file_contents!(mnt, "/tags/my/very/cool/path.tags", "kind=cool\n");
+    assert_dir_children!(mnt, "/kind/great", &["file", "path"]);
+    assert_dir_children!(mnt, "/kind/cool", &["file.0", "file.1", "path.0",
+        "path.1"]);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_tags_files() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+
+    assert_file_contents!(
+        mnt, "/tags/my/other/super/cool/file.tags",
+        "type=awesome\ntype=cool\n"
+    );
+
+    // writing a tags file replaces the manual tags of its path.
+    let tags_file = format!("{mnt}/tags/my/very/cool/path.tags");
+    std::fs::write(&tags_file,
+        "type=cool\nrating=very\\ good\n\nfavourite\n")?;
+    assert_file_contents!(
+        mnt, "/tags/my/very/cool/path.tags",
+        "type=cool\nrating=very\\ good\nfavourite\n"
+    );
+    assert_dir_children!(mnt, "/favourite", &["path", "rating", "type"]);
+    assert_dir_children!(mnt, "/type/cool/rating", &["very good"]);
+
+    // nesting stops at the maximum depth.
+    assert_dir_children!(mnt, "/type/cool/rating/very good", &["path"]);
+
+    // invalid contents are rejected when the file is closed.
+    let mut file = std::fs::OpenOptions::new()
+        .write(true).truncate(true).open(&tags_file)?;
+    file.write_all(b"type=cool\nnot a valid tag\n")?;
+    let res = unsafe { libc::close(file.into_raw_fd()) };
+    assert_eq!(res, -1);
+    assert_file_contents!(
+        mnt, "/tags/my/very/cool/path.tags",
+        "type=cool\nrating=very\\ good\nfavourite\n"
+    );
+
+    // tags files report the size of their contents, so appending adds a tag.
+    let contents = "type=cool\nrating=very\\ good\nfavourite\n";
+    assert_eq!(std::fs::metadata(&tags_file)?.len(), contents.len() as u64);
+    let mut file = std::fs::OpenOptions::new().append(true).open(&tags_file)?;
+    file.write_all(b"new\n")?;
+    assert_eq!(unsafe { libc::close(file.into_raw_fd()) }, 0);
+    assert_file_contents!(
+        mnt, "/tags/my/very/cool/path.tags",
+        "type=cool\nrating=very\\ good\nfavourite\nnew\n"
+    );
+
+    // deleting a tags file removes all tags from its path.
+    std::fs::remove_file(&tags_file)?;
+    assert!(db.tags("/my/very/cool/path")?.is_empty());
+    assert_dir_children!(mnt, "/", &["?", "tags", "type"]);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_statfs() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+
+    // the tagged paths are reported as the inodes of the filesystem, all of
+    // them in use.
+    let mount_path = CString::new(mnt.as_str())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    assert_eq!(
+        unsafe { libc::statvfs(mount_path.as_ptr(), stat.as_mut_ptr()) },
+        0
+    );
+    let stat = unsafe { stat.assume_init() };
+    assert_eq!(stat.f_files, db.path_count()?);
+    assert_eq!(stat.f_ffree, 0);
+    assert_eq!(stat.f_blocks, 0);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_control_dir() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+
+    // the hidden control directory gives access to the database through the
+    // mount alone.
+    assert_dir_children!(mnt, "/.tagfs", &["invalid-paths", "query",
+        "stats", "stored-queries", "version"]);
+    assert_file_contents!(mnt, "/.tagfs/version",
+        format!("{}\n", env!("CARGO_PKG_VERSION")));
+    assert_file_contents!(mnt, "/.tagfs/stats",
+        format!("tags: {}\npaths: {}\nmappings: {}\n", db.tag_count()?,
+            db.path_count()?, db.mapping_count()?));
+    assert!(std::fs::write(format!("{mnt}/.tagfs/stats"), "").is_err());
+
+    // a query written to the query file is read back as the paths that
+    // match it from the same handle, other handles have their own query.
+    let query_file = format!("{mnt}/.tagfs/query");
+    assert_file_contents!(mnt, "/.tagfs/query", "");
+    let mut file = std::fs::OpenOptions::new()
+        .read(true).write(true).open(&query_file)?;
+    file.write_all(b"type==cool\n")?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut results = String::new();
+    file.read_to_string(&mut results)?;
+    let expected = db.query("type==cool", false)?.into_iter()
+        .map(|(path, _)| format!("{path}\n")).collect::<String>();
+    assert!(!expected.is_empty());
+    assert_eq!(results, expected);
+    assert_file_contents!(mnt, "/.tagfs/query", "");
+    drop(file);
+
+    // an invalid query is rejected when it is read or the file is closed.
+    let mut file = std::fs::OpenOptions::new()
+        .read(true).write(true).open(&query_file)?;
+    file.write_all(b"type==cool and")?;
+    file.seek(SeekFrom::Start(0))?;
+    assert!(file.read_to_string(&mut String::new()).is_err());
+    let res = unsafe { libc::close(file.into_raw_fd()) };
+    assert_eq!(res, -1);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_xattrs() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+
+    // tags are exposed as extended attributes.
+    let tags_file = "/tags/my/other/very/cool/path.tags";
+    assert_eq!(listxattr(&mnt, tags_file), &["user.tagfs.type"]);
+    assert_eq!(listxattr(&mnt, "/type/cool/path"), &["user.tagfs.type"]);
+    assert_eq!(getxattr(&mnt, tags_file, "user.tagfs.type").unwrap(),
+        b"cool");
+    assert!(getxattr(&mnt, tags_file, "user.tagfs.genre").is_none());
+
+    assert!(setxattr(&mnt, tags_file, "user.tagfs.genre",
+        b"crime\nthriller"));
+    assert!(setxattr(&mnt, tags_file, "user.tagfs.favourite", b""));
+    assert_eq!(
+        getxattr(&mnt, tags_file, "user.tagfs.genre").unwrap(),
+        b"crime\nthriller"
+    );
+    assert_file_contents!(
+        mnt, tags_file,
+        "type=cool\ngenre=crime\ngenre=thriller\nfavourite\n"
+    );
+
+    assert!(!setxattr(&mnt, tags_file, "user.other", b"value"));
+
+    let path = CString::new(format!("{mnt}{tags_file}"))?;
+    let name = CString::new("user.tagfs.genre")?;
+    assert_eq!(unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) }, 0);
+    assert_eq!(
+        listxattr(&mnt, tags_file),
+        &["user.tagfs.favourite", "user.tagfs.type"]
+    );
+
+    // removing an attribute removes every value of the tag, and a tag that
+    // is not on the path has no attribute to remove.
+    assert!(getxattr(&mnt, tags_file, "user.tagfs.genre").is_none());
+    assert!(db.tags("/my/other/very/cool/path")?.iter()
+        .all(|tag_mapping| tag_mapping.tag.name != "genre"));
+    assert_eq!(unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) }, -1);
+    assert_eq!(std::io::Error::last_os_error().raw_os_error(),
+        Some(libc::ENODATA));
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_db_changes() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+    db.tag("/my/other/super/cool/file", "favourite", None)?;
+
+    // changes made to the database while mounted are picked up once the
+    // kernel cache expires.
+    assert_symlink_target!(mnt, "/favourite/file",
+        "/my/other/super/cool/file");
+    db.untag("/my/other/super/cool/file", "favourite", None)?;
+    db.tag("/my/other/very/cool/file", "favourite", None)?;
+    std::thread::sleep(Duration::from_millis(1100));
+    assert_symlink_target!(mnt, "/favourite/file",
+        "/my/other/very/cool/file");
+
+    // a stale link is an error rather than the end of the mount.
+    db.untag("/my/other/very/cool/file", "favourite", None)?;
+    assert!(std::fs::read_link(format!("{mnt}/favourite/file")).is_err());
+    assert_dir_children!(mnt, "/", &["?", "tags", "type"]);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_invalidation() -> Result<()> {
+    let config = MountConfig {
+        ttl: Duration::from_secs(60),
+        poll_interval: Duration::from_millis(100),
+        ..MountConfig::default()
+    };
+    let mut mount = mount(config)?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    db.tag("/my/other/super/cool/path", "mapped", None)?;
+
+    // the kernel is told to forget cached entries when the database changes,
+    // long before they expire.
+    let cached_link = format!("{mnt}/mapped/path");
+    assert!(std::fs::symlink_metadata(&cached_link).is_ok());
+    db.untag("/my/other/super/cool/path", "mapped", None)?;
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(std::fs::symlink_metadata(&cached_link).is_err());
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_hidden_tags() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+
+    // a tag with the same name as a directory is hidden by it.
+    db.tag("/my/other/very/cool/file", "tags", None)?;
+    assert_dir_children!(mnt, "/", &["?", "tags", "type"]);
+    assert_dir_children!(mnt, "/tags", &["my"]);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_threads() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+
+    // requests are served on several threads, so directories can be listed
+    // at the same time.
+    let listings = (0..8)
+        .map(|_| {
+            let mnt = mnt.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10 {
+                    assert_dir_children!(mnt, "/", &["?", "tags", "type"]);
+                    assert_dir_children!(mnt, "/tags", &["my"]);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for listing in listings {
+        assert!(listing.join().is_ok());
+    }
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_real_paths() -> Result<()> {
+    let mut mount = mount(config())?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    let tmp_real = mktemp::Temp::new_dir()?;
+    let real_path = tmp_real.as_os_str().to_str().unwrap();
+    let real_dir_path = format!("{real_path}/dir");
+    std::fs::create_dir(&real_dir_path)?;
+    db.tag(&real_dir_path, "real", None)?;
+    assert_symlink_target!(mnt, "/real/dir", real_dir_path);
+
+    // links report the times of the paths they refer to.
+    let link = std::fs::symlink_metadata(format!("{mnt}/real/dir"))?;
+    let target = std::fs::symlink_metadata(&real_dir_path)?;
+    assert_eq!(link.modified()?, target.modified()?);
+
+    // paths that are not valid UTF-8 are shown as they are on disk.
+    let latin1_name = OsStr::from_bytes(b"caf\xe9.txt");
+    let latin1_path = Path::new(real_path).join(latin1_name);
+    std::fs::write(&latin1_path, "latin-1")?;
+    db.tag(&encode(latin1_path.as_os_str()), "real", None)?;
+    let latin1_link = Path::new(&mnt).join("real").join(latin1_name);
+    assert_eq!(std::fs::read_link(latin1_link)?, latin1_path);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_passthrough() -> Result<()> {
+    let config = MountConfig {
+        max_depth: 2,
+        passthrough: true,
+        all_tags_dir_name: String::from("all"),
+        ..MountConfig::default()
+    };
+    let mut mount = mount(config)?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    let tmp_real = mktemp::Temp::new_dir()?;
+    let real_path = tmp_real.as_os_str().to_str().unwrap();
+    let real_dir_path = format!("{real_path}/dir");
+    std::fs::create_dir(&real_dir_path)?;
+    std::fs::write(format!("{real_path}/dir/file.txt"), "contents")?;
+    std::fs::write(format!("{real_path}/file.txt"), "more contents")?;
+    let latin1_name = OsStr::from_bytes(b"caf\xe9.txt");
+    let latin1_path = Path::new(real_path).join(latin1_name);
+    std::fs::write(&latin1_path, "latin-1")?;
+    db.tag(&real_dir_path, "real", None)?;
+    db.tag(&format!("{real_path}/file.txt"), "real", None)?;
+    db.tag(&encode(latin1_path.as_os_str()), "real", None)?;
+    db.tag("/my/other/very/cool/file", "tags", None)?;
+
+    // renaming the all tags directory shows the tag with the same name.
+    let root = std::fs::read_dir(&mnt)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert!(root.contains(&OsString::from("all")));
+    assert!(root.contains(&OsString::from("tags")));
+    assert!(Path::new(&format!("{mnt}/tags/file")).is_symlink());
+
+    // links to paths that exist are shown as the real files and
+    // directories.
+    let real_dir = format!("{mnt}/real");
+    let meta = std::fs::symlink_metadata(format!("{real_dir}/dir"))?;
+    assert!(meta.is_dir());
+    assert_eq!(
+        std::fs::read_to_string(format!("{real_dir}/file.txt"))?,
+        "more contents"
+    );
+    assert_eq!(
+        std::fs::read_to_string(format!("{real_dir}/dir/file.txt"))?,
+        "contents"
+    );
+    assert!(std::fs::write(format!("{real_dir}/file.txt"), "").is_err());
+    assert_eq!(
+        std::fs::read_to_string(Path::new(&real_dir).join(latin1_name))?,
+        "latin-1"
+    );
+
+    // links are real files, so their tags can be read as extended
+    // attributes.
+    let real_file = CString::new(format!("{real_dir}/file.txt"))?;
+    let name = CString::new("user.tagfs.real")?;
+    let size = unsafe {
+        libc::lgetxattr(real_file.as_ptr(), name.as_ptr(),
+            std::ptr::null_mut(), 0)
+    };
+    assert_eq!(size, 0);
+
+    // removing a real directory only removes the tag.
+    std::fs::remove_dir(format!("{real_dir}/dir"))?;
+    assert!(db.tags(&real_dir_path)?.is_empty());
+    assert!(Path::new(&format!("{real_path}/dir/file.txt")).exists());
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_relative_links() -> Result<()> {
+    let config = MountConfig { relative_links: true, ..config() };
+    let mut mount = mount(config)?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    let tmp_real = mktemp::Temp::new_dir()?;
+    let real_path = tmp_real.as_os_str().to_str().unwrap();
+    std::fs::write(format!("{real_path}/file.txt"), "more contents")?;
+    db.tag(&format!("{real_path}/file.txt"), "real", None)?;
+
+    // relative links lead to the same file from wherever the mount is.
+    let real_file = format!("{mnt}/real/file.txt");
+    assert!(std::fs::read_link(&real_file)?.is_relative());
+    assert_eq!(std::fs::read_to_string(&real_file)?, "more contents");
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_map_roots() -> Result<()> {
+    let config = MountConfig {
+        map_roots: vec![
+            (String::from("/my/other"), String::from("/mnt/other")),
+        ],
+        ..config()
+    };
+    let mut mount = mount(config)?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    // mapped roots are replaced in the targets of links, and links created
+    // to a mapped root tag the path in the database.
+    db.tag("/my/other/very/cool/file", "mapped", None)?;
+    assert_symlink_target!(mnt, "/mapped/file", "/mnt/other/very/cool/file");
+
+    std::os::unix::fs::symlink("/mnt/other/super/cool/path",
+        format!("{mnt}/mapped/path"))?;
+    assert!(db.tags("/my/other/super/cool/path")?.iter()
+        .any(|tag_mapping| tag_mapping.tag.name == "mapped"));
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_root_query() -> Result<()> {
+    let config = MountConfig {
+        root_query: Some(String::from("mapped")),
+        ..config()
+    };
+    let mut mount = mount(config)?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+    db.tag("/my/other/very/cool/file", "mapped", None)?;
+
+    // only the paths that match the root query are shown.
+    assert_dir_children!(mnt, "/tags/my/other", &["very"]);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn fs_duplicate_names() -> Result<()> {
+    let config = MountConfig {
+        duplicate_names: DuplicateNames::Counter,
+        ..config()
+    };
+    let mut mount = mount(config)?;
+    let (mnt, db) = (mount.path.clone(), &mut mount.db);
+
+    tag_paths(db)?;
+
+    // links to paths with the same name are numbered by the id of their tag
+    // mapping, which follows the order the paths were tagged in.
+    assert_dir_children!(mnt, "/type/awesome",
+        &["file", "path (1)", "path (3)"]);
+    assert_dir_children!(mnt, "/type/cool", &["file (2)", "file (6)", "path"]);
+
+    // untagging the first of the paths does not rename the others.
+    db.tag("/my/third/file", "type", Some("cool"))?;
+    db.untag("/my/other/very/cool/file", "type", Some("cool"))?;
+    assert_dir_children!(mnt, "/type/cool", &["file (6)", "file (7)", "path"]);
+
+    // new links are created under the number their mapping will be given.
+    std::os::unix::fs::symlink("/my/fourth/file",
+        format!("{mnt}/type/cool/file (8)"))?;
+    assert_symlink_target!(mnt, "/type/cool/file (8)", "/my/fourth/file");
+    std::fs::rename(format!("{mnt}/type/cool/path"),
+        format!("{mnt}/type/awesome/path (9)"))?;
+    assert_dir_children!(mnt, "/type/awesome",
+        &["file", "path (1)", "path (3)", "path (9)"]);
+    assert_dir_children!(mnt, "/type/cool",
+        &["file (6)", "file (7)", "file (8)"]);
 
     Ok(())
 }